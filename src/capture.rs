@@ -0,0 +1,498 @@
+use anyhow::Result;
+
+/// How a texture's rows are laid out once copied into a mappable buffer
+///
+/// The canvas shares the surface format, so depending on the platform the data
+/// comes back as BGRA or RGBA. sRGB formats already hold sRGB encoded bytes,
+/// which is what PNG/GIF expect, so only the channel order needs fixing up.
+#[derive(Clone, Copy, Debug)]
+struct Readback {
+    width: u32,
+    height: u32,
+    bgra: bool,
+    padded_bytes_per_row: u32,
+}
+impl Readback {
+    fn new(texture: &wgpu::Texture) -> Result<Self> {
+        use wgpu::TextureFormat::*;
+        let bgra = match texture.format() {
+            Rgba8Unorm | Rgba8UnormSrgb => false,
+            Bgra8Unorm | Bgra8UnormSrgb => true,
+            format => anyhow::bail!("unsupported texture format for capture: {:?}", format),
+        };
+        // Rows in the staging buffer have to be aligned to COPY_BYTES_PER_ROW_ALIGNMENT
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (texture.width() * 4).div_ceil(align) * align;
+        Ok(Self {
+            width: texture.width(),
+            height: texture.height(),
+            bgra,
+            padded_bytes_per_row,
+        })
+    }
+    fn create_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Grainboy Capture Buffer"),
+            size: (self.padded_bytes_per_row * self.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        })
+    }
+    /// Copies `texture` into `buffer` and starts mapping it, `rx` fires once it is readable
+    fn copy(
+        &self,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+        texture: &wgpu::Texture,
+        buffer: &wgpu::Buffer,
+    ) -> std::sync::mpsc::Receiver<Result<(), wgpu::BufferAsyncError>> {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Grainboy Capture Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(self.padded_bytes_per_row),
+                    rows_per_image: Some(self.height),
+                },
+            },
+            texture.size(),
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+        let (tx, rx) = std::sync::mpsc::channel();
+        buffer.slice(..).map_async(wgpu::MapMode::Read, move |res| {
+            let _ = tx.send(res);
+        });
+        rx
+    }
+    /// Unpads the rows of a mapped buffer and unmaps it
+    fn read_image(&self, buffer: &wgpu::Buffer) -> Result<image::RgbaImage> {
+        let unpadded_bytes_per_row = self.width * 4;
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * self.height) as usize);
+        {
+            let data = buffer.slice(..).get_mapped_range();
+            for row in data.chunks(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        buffer.unmap();
+        if self.bgra {
+            for px in pixels.chunks_exact_mut(4) {
+                px.swap(0, 2);
+            }
+        }
+        image::RgbaImage::from_raw(self.width, self.height, pixels)
+            .ok_or_else(|| anyhow::anyhow!("capture buffer has unexpected size"))
+    }
+}
+
+/// Copies a texture back to the CPU as an RGBA image, waiting for the GPU to finish
+pub fn read_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+) -> Result<image::RgbaImage> {
+    let readback = Readback::new(texture)?;
+    let buffer = readback.create_buffer(device);
+    let rx = readback.copy(queue, device, texture, &buffer);
+    device.poll(wgpu::Maintain::Wait);
+    rx.recv()??;
+    readback.read_image(&buffer)
+}
+
+/// Reads frames back from the GPU without waiting for each one
+///
+/// Copies go through a small ring of staging buffers and come out in order, a
+/// few frames later. It only blocks when every buffer is still in flight.
+#[derive(Debug)]
+pub struct FrameReader {
+    readback: Readback,
+    free: Vec<wgpu::Buffer>,
+    pending: std::collections::VecDeque<(
+        wgpu::Buffer,
+        std::sync::mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>,
+    )>,
+}
+impl FrameReader {
+    pub const RING_SIZE: usize = 3;
+    pub fn new(device: &wgpu::Device, texture: &wgpu::Texture) -> Result<Self> {
+        let readback = Readback::new(texture)?;
+        let free = (0..Self::RING_SIZE)
+            .map(|_| readback.create_buffer(device))
+            .collect();
+        Ok(Self {
+            readback,
+            free,
+            pending: Default::default(),
+        })
+    }
+    /// Starts reading `texture` back, returns the earlier frames that finished since
+    pub fn read(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+    ) -> Result<Vec<image::RgbaImage>> {
+        device.poll(wgpu::Maintain::Poll);
+        let mut frames = self.collect()?;
+        if self.free.is_empty() {
+            device.poll(wgpu::Maintain::Wait);
+            frames.extend(self.collect()?);
+        }
+        let Some(buffer) = self.free.pop() else {
+            anyhow::bail!("no capture buffer came back from the GPU");
+        };
+        let rx = self.readback.copy(queue, device, texture, &buffer);
+        self.pending.push_back((buffer, rx));
+        Ok(frames)
+    }
+    /// Waits for the frames still in flight
+    pub fn finish(mut self, device: &wgpu::Device) -> Result<Vec<image::RgbaImage>> {
+        device.poll(wgpu::Maintain::Wait);
+        self.collect()
+    }
+    fn collect(&mut self) -> Result<Vec<image::RgbaImage>> {
+        let mut frames = vec![];
+        while let Some((_, rx)) = self.pending.front() {
+            match rx.try_recv() {
+                Ok(res) => {
+                    let (buffer, _) = self.pending.pop_front().unwrap();
+                    res?;
+                    frames.push(self.readback.read_image(&buffer)?);
+                    self.free.push(buffer);
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => break,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    anyhow::bail!("capture buffer mapping was dropped")
+                }
+            }
+        }
+        Ok(frames)
+    }
+}
+
+/// Scales an image up by an integer factor using nearest-neighbor sampling
+pub fn upscale(image: image::RgbaImage, scale: u32) -> image::RgbaImage {
+    if scale <= 1 {
+        return image;
+    }
+    let (w, h) = image.dimensions();
+    image::imageops::resize(
+        &image,
+        w * scale,
+        h * scale,
+        image::imageops::FilterType::Nearest,
+    )
+}
+
+/// Writes a PNG named after the current time into `dir` and returns its path
+pub fn save_screenshot(
+    image: &image::RgbaImage,
+    dir: &std::path::Path,
+    scale: u32,
+) -> Result<std::path::PathBuf> {
+    let filename = format!(
+        "grainboy-{}.png",
+        chrono::Local::now().format("%Y%m%d-%H%M%S%.3f")
+    );
+    let path = dir.join(filename);
+    upscale(image.clone(), scale).save_with_format(&path, image::ImageFormat::Png)?;
+    Ok(path)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordingFormat {
+    Gif,
+    Apng,
+}
+impl RecordingFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::Gif => "gif",
+            RecordingFormat::Apng => "png",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RecordingOptions {
+    pub format: RecordingFormat,
+    /// Integer upscale factor applied to every frame
+    pub scale: u32,
+    /// Recording stops on its own once it reaches this length
+    pub max_duration: instant::Duration,
+}
+impl Default for RecordingOptions {
+    fn default() -> Self {
+        Self {
+            format: RecordingFormat::Gif,
+            scale: 1,
+            max_duration: instant::Duration::from_secs(10),
+        }
+    }
+}
+
+/// Streams captured canvas frames to an encoder running on a background thread
+#[derive(Debug)]
+pub struct Recorder {
+    /// Frames with the number of updates each one lasts
+    sender: std::sync::mpsc::SyncSender<(image::RgbaImage, u32)>,
+    worker: std::thread::JoinHandle<Result<std::path::PathBuf>>,
+    reader: Option<FrameReader>,
+    /// Update counts of the frames the GPU is still copying
+    pending_steps: std::collections::VecDeque<u32>,
+    updates: usize,
+    max_updates: usize,
+}
+impl Recorder {
+    /// Frames waiting for the encoder, capturing blocks once it falls this far behind
+    pub const QUEUE_LEN: usize = 60;
+    pub fn start(
+        dir: &std::path::Path,
+        frame_dur: instant::Duration,
+        options: RecordingOptions,
+    ) -> Self {
+        let filename = format!(
+            "grainboy-{}.{}",
+            chrono::Local::now().format("%Y%m%d-%H%M%S%.3f"),
+            options.format.extension()
+        );
+        let path = dir.join(filename);
+        let (sender, receiver) = std::sync::mpsc::sync_channel(Self::QUEUE_LEN);
+        let worker = std::thread::spawn(move || encode(path, receiver, frame_dur, options));
+        let max_updates =
+            (options.max_duration.as_secs_f32() / frame_dur.as_secs_f32()).ceil() as usize;
+        Self {
+            sender,
+            worker,
+            reader: None,
+            pending_steps: std::collections::VecDeque::new(),
+            updates: 0,
+            max_updates,
+        }
+    }
+    /// Captures `texture` as the next frame, returns `false` once the recording is full
+    ///
+    /// The frame is shown for `steps` updates, more than one when the game loop
+    /// caught up on updates that were never presented. Frames reach the encoder
+    /// a few calls later, once the GPU has copied them.
+    pub fn capture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        steps: u32,
+    ) -> Result<bool> {
+        if self.updates >= self.max_updates {
+            return Ok(false);
+        }
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => self.reader.insert(FrameReader::new(device, texture)?),
+        };
+        self.pending_steps.push_back(steps.max(1));
+        for frame in reader.read(device, queue, texture)? {
+            let steps = self.pending_steps.pop_front().unwrap_or(1);
+            self.sender.send((frame, steps))?;
+        }
+        self.updates += steps.max(1) as usize;
+        Ok(self.updates < self.max_updates)
+    }
+    /// Closes the recording; the returned handle resolves once the file is written
+    pub fn stop(
+        mut self,
+        device: &wgpu::Device,
+    ) -> std::thread::JoinHandle<Result<std::path::PathBuf>> {
+        if let Some(reader) = self.reader {
+            match reader.finish(device) {
+                Ok(frames) => {
+                    for frame in frames {
+                        let steps = self.pending_steps.pop_front().unwrap_or(1);
+                        if self.sender.send((frame, steps)).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(err) => eprintln!("Error capturing recording frame: {:?}", err),
+            }
+        }
+        drop(self.sender);
+        self.worker
+    }
+}
+
+fn encode(
+    path: std::path::PathBuf,
+    frames: std::sync::mpsc::Receiver<(image::RgbaImage, u32)>,
+    frame_dur: instant::Duration,
+    options: RecordingOptions,
+) -> Result<std::path::PathBuf> {
+    let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+    match options.format {
+        RecordingFormat::Gif => {
+            use image::codecs::gif::{GifEncoder, Repeat};
+            // NeuQuant palette quantization, trading a little quality for speed
+            let mut encoder = GifEncoder::new_with_speed(file, 10);
+            encoder.set_repeat(Repeat::Infinite)?;
+            // GIF delays are whole centiseconds, so each frame is shown until the time it
+            // should have ended, rounded. Frames that would get under the 2cs most
+            // viewers honor are skipped and their time goes to the next one.
+            let mut elapsed = instant::Duration::ZERO;
+            let mut shown_cs = 0;
+            let mut count = 0;
+            for (frame, steps) in frames {
+                elapsed += frame_dur * steps;
+                let end_cs = (elapsed.as_secs_f64() * 100.).round() as u32;
+                let delay_cs = end_cs - shown_cs;
+                if delay_cs < 2 {
+                    continue;
+                }
+                shown_cs = end_cs;
+                let delay = image::Delay::from_numer_denom_ms(delay_cs * 10, 1);
+                let frame = upscale(frame, options.scale);
+                encoder.encode_frame(image::Frame::from_parts(frame, 0, 0, delay))?;
+                count += 1;
+            }
+            if count == 0 {
+                anyhow::bail!("no frames were recorded");
+            }
+        }
+        RecordingFormat::Apng => {
+            // APNG needs the frame count up front, so frames wait in a raw file next
+            // to the recording rather than in memory
+            let raw_path = path.with_extension("frames");
+            let result = encode_apng(file, &raw_path, frames, frame_dur, options.scale);
+            let _ = std::fs::remove_file(&raw_path);
+            result?;
+        }
+    }
+    Ok(path)
+}
+
+fn encode_apng(
+    file: impl std::io::Write,
+    raw_path: &std::path::Path,
+    frames: std::sync::mpsc::Receiver<(image::RgbaImage, u32)>,
+    frame_dur: instant::Duration,
+    scale: u32,
+) -> Result<()> {
+    use std::io::{Read, Write};
+    let mut raw = std::io::BufWriter::new(std::fs::File::create(raw_path)?);
+    let mut size = None;
+    let mut frame_steps = vec![];
+    for (frame, steps) in frames {
+        if *size.get_or_insert(frame.dimensions()) != frame.dimensions() {
+            anyhow::bail!("recorded frames changed size");
+        }
+        raw.write_all(&frame)?;
+        frame_steps.push(steps);
+    }
+    raw.flush()?;
+    drop(raw);
+    let Some((w, h)) = size else {
+        anyhow::bail!("no frames were recorded");
+    };
+    let scale = scale.max(1);
+    let mut encoder = png::Encoder::new(file, w * scale, h * scale);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frame_steps.len() as u32, 0)?;
+    let mut writer = encoder.write_header()?;
+    let mut raw = std::io::BufReader::new(std::fs::File::open(raw_path)?);
+    for steps in frame_steps {
+        let mut pixels = vec![0; w as usize * h as usize * 4];
+        raw.read_exact(&mut pixels)?;
+        let Some(frame) = image::RgbaImage::from_raw(w, h, pixels) else {
+            anyhow::bail!("recorded frame has the wrong size");
+        };
+        let (numer, denom) = apng_delay(frame_dur, steps);
+        writer.set_frame_delay(numer, denom)?;
+        writer.write_image_data(&upscale(frame, scale))?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// How long a frame lasting `steps` updates is shown, as a fraction of a second
+///
+/// The update rate gives an exact one, other frame durations are rounded to milliseconds.
+fn apng_delay(frame_dur: instant::Duration, steps: u32) -> (u16, u16) {
+    let rate = (1. / frame_dur.as_secs_f64()).round();
+    let exact = (frame_dur.as_secs_f64() * rate - 1.).abs() < 1e-6;
+    match exact && rate >= 1. && rate <= u16::MAX as f64 && steps <= u16::MAX as u32 {
+        true => (steps as u16, rate as u16),
+        false => {
+            let ms = (frame_dur * steps).as_millis();
+            (ms.min(u16::MAX as u128) as u16, 1000)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes 2x1 frames lasting the given numbers of 60fps updates
+    fn record(format: RecordingFormat, steps: &[u32]) -> std::path::PathBuf {
+        let name = format!("grainboy-test-{}-{:?}", std::process::id(), format);
+        let path = std::env::temp_dir().join(name);
+        let (sender, receiver) = std::sync::mpsc::sync_channel(steps.len());
+        for (i, &steps) in steps.iter().enumerate() {
+            let frame = image::RgbaImage::from_pixel(2, 1, image::Rgba([i as u8, 0, 0, 255]));
+            sender.send((frame, steps)).unwrap();
+        }
+        drop(sender);
+        let options = RecordingOptions {
+            format,
+            ..RecordingOptions::default()
+        };
+        let frame_dur = instant::Duration::from_secs(1) / 60;
+        encode(path, receiver, frame_dur, options).unwrap()
+    }
+
+    #[test]
+    fn gif_frames_last_their_updates() {
+        use image::AnimationDecoder;
+        let path = record(RecordingFormat::Gif, &[3, 3, 6]);
+        let file = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+        let decoder = image::codecs::gif::GifDecoder::new(file).unwrap();
+        let delays: Vec<u32> = decoder
+            .into_frames()
+            .map(|frame| frame.unwrap().delay().numer_denom_ms().0)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(delays, vec![50, 50, 100]);
+    }
+
+    #[test]
+    fn apng_frames_last_their_updates() {
+        let path = record(RecordingFormat::Apng, &[1, 5]);
+        let decoder = png::Decoder::new(std::fs::File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let mut delays = vec![];
+        while reader.next_frame(&mut buf).is_ok() {
+            let control = reader.info().frame_control.unwrap();
+            delays.push((control.delay_num, control.delay_den));
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(delays, vec![(1, 60), (5, 60)]);
+        // The raw frames only live on disk while encoding
+        assert!(!path.with_extension("frames").exists());
+    }
+
+    #[test]
+    fn apng_delay_falls_back_to_milliseconds() {
+        let frame_dur = instant::Duration::from_millis(1500);
+        assert_eq!(apng_delay(frame_dur, 2), (3000, 1000));
+        assert_eq!(apng_delay(instant::Duration::from_secs(1) / 30, 2), (2, 30));
+    }
+}
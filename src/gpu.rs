@@ -1,57 +1,110 @@
 #![allow(unused)]
 
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::Div;
 
 #[derive(Debug)]
 pub struct Renderer<'a> {
     pub globals: Globals,
+    /// Time between cart updates, recordings play back at this rate
     pub frame_dur: instant::Duration,
-    pub first_tick: instant::Instant,
+    pub perf: crate::perf::PerfStats,
+    pub scale_mode: ScaleMode,
+    pub post_effects: crate::post::PostEffects,
+    /// Most quads a frame can draw, the device's buffer size limit also applies
+    pub max_quads: usize,
     u_globals: UniformBuffer<Globals>,
+    u_surface_globals: UniformBuffer<Globals>,
+    u_palette: UniformBuffer<Palette>,
     v_surface: VertexBuffer<'a>,
+    /// Performance HUD, drawn over the canvas in the surface pass
+    v_hud: VertexBuffer<'a>,
     v_canvas: VertexBuffer<'a>,
     canvas: Canvas,
+    /// Offscreen targets, target n is at n - 1
+    targets: Vec<RenderTarget>,
     spritesheet: Spritesheet,
+    /// The built-in fonts for the HUD, carts can replace the spritesheet's font page
+    hud_fonts: Spritesheet,
     pipeline: wgpu::RenderPipeline,
+    post: crate::post::PostProcess,
     clear_color: wgpu::Color,
+    /// The cart wrote the canvas' pixels this frame, so it isn't cleared
+    framebuffer: bool,
+    /// A cart update is waiting to be drawn onto the canvas
+    new_frame: bool,
+    /// Updates since the canvas was last drawn, which its recorded frame lasts
+    steps: u32,
+    /// The canvas has something on it worth presenting
+    canvas_drawn: bool,
+    /// When the first GPU work of the frame being rendered was submitted
+    first_submit: Option<instant::Instant>,
+    batches: Vec<DrawBatch>,
+    recorder: Option<crate::capture::Recorder>,
+    recordings: Vec<std::thread::JoinHandle<anyhow::Result<std::path::PathBuf>>>,
 }
 impl<'a> Renderer<'a> {
     pub fn new(gpu: &GPUContext) -> Self {
         let shader_module = gpu
             .device
             .create_shader_module(wgpu::include_wgsl!("main.wgsl"));
-        let v_canvas = VertexBuffer::new(&gpu.device);
-        let canvas = Canvas::new(&gpu.device, &gpu.queue, &gpu.config.format, 256, 144);
-        let mut v_surface = VertexBuffer::new(&gpu.device);
+        let v_canvas = VertexBuffer::new(&gpu.device, VertexBuffer::INITIAL_CAPACITY);
+        let [width, height] = Canvas::SIZE;
+        let canvas = Canvas::new(&gpu.device, &gpu.queue, &gpu.config.format, width, height);
+        // The surface pass only ever draws the canvas itself
+        let mut v_surface = VertexBuffer::new(&gpu.device, 1);
         v_surface.write(&gpu.queue, 0, &canvas.vertex_bytes.clone());
+        let v_hud = VertexBuffer::new(&gpu.device, 64);
         let globals = Globals::new([
             canvas.texture.width() as f32,
             canvas.texture.height() as f32,
         ]);
         let u_globals = UniformBuffer::new(&gpu.device, globals);
+        // The surface pass always draws the whole canvas, regardless of the cart's zoom
+        let u_surface_globals = UniformBuffer::new(&gpu.device, globals);
+        let u_palette = UniformBuffer::new(&gpu.device, Palette::DEFAULT);
         let spritesheet = Spritesheet::new(&gpu.device, &gpu.queue);
+        let hud_fonts =
+            Spritesheet::with_pages(&gpu.device, &gpu.queue, Spritesheet::FONT_PAGE + 1);
         let pipeline = Self::create_render_pipeline(
             gpu,
-            &[&u_globals.layout, &spritesheet.layout],
+            &[&u_globals.layout, &spritesheet.layout, &u_palette.layout],
             &shader_module,
             v_canvas.layouts,
         );
+        let post = crate::post::PostProcess::new(gpu, &canvas.layout);
         Self {
             globals,
             frame_dur: instant::Duration::from_secs(1).div(60),
-            first_tick: instant::Instant::now(),
+            perf: crate::perf::PerfStats::new(),
+            scale_mode: ScaleMode::AspectFit,
+            post_effects: crate::post::PostEffects::default(),
+            max_quads: VertexBuffer::DEFAULT_MAX_QUADS,
             u_globals,
+            u_surface_globals,
+            u_palette,
             v_surface,
+            v_hud,
             v_canvas,
             canvas,
+            targets: vec![],
             spritesheet,
+            hud_fonts,
             pipeline,
+            post,
             clear_color: wgpu::Color {
                 r: 0.,
                 g: 0.,
                 b: 0.,
                 a: 1.0,
             },
+            framebuffer: false,
+            new_frame: false,
+            steps: 0,
+            canvas_drawn: false,
+            first_submit: None,
+            batches: vec![],
+            recorder: None,
+            recordings: vec![],
         }
     }
     fn create_render_pipeline(
@@ -102,27 +155,278 @@ impl<'a> Renderer<'a> {
     pub fn set_clear_color(&mut self, color: wgpu::Color) {
         self.clear_color = color;
     }
-    pub fn write_vertexes(&mut self, gpu: &GPUContext, data: &[u8]) {
-        self.v_canvas.write(&gpu.queue, 0, data);
+    /// Uploads the frame's quads, `batches` may be empty to draw them all unclipped
+    ///
+    /// Quads past `max_quads` are dropped with an error, the rest are still drawn.
+    pub fn write_vertexes(
+        &mut self,
+        gpu: &GPUContext,
+        data: &[u8],
+        batches: &[DrawBatch],
+    ) -> anyhow::Result<()> {
+        let size = std::mem::size_of::<QuadVertex>();
+        let max_quads = self.max_quads.min(VertexBuffer::device_max(&gpu.device));
+        let drawn = data.len() / size;
+        let quad_count = drawn.min(max_quads);
+        self.v_canvas.reserve(&gpu.device, quad_count, max_quads);
+        self.v_canvas
+            .write(&gpu.queue, 0, &data[..quad_count * size]);
+        self.batches.clear();
+        if batches.is_empty() {
+            self.batches.push(DrawBatch {
+                quads: 0..quad_count as u32,
+                clip: None,
+                target: 0,
+                source: 0,
+            });
+        } else {
+            let quad_count = quad_count as u32;
+            let kept = batches
+                .iter()
+                .filter(|batch| batch.quads.start < quad_count);
+            self.batches.extend(kept.map(|batch| DrawBatch {
+                quads: batch.quads.start..batch.quads.end.min(quad_count),
+                ..batch.clone()
+            }));
+        }
+        self.perf.record_upload(quad_count, quad_count * size);
+        if quad_count < drawn {
+            anyhow::bail!(
+                "the cart drew {drawn} quads but max_quads is {max_quads}, only {quad_count} were drawn"
+            );
+        }
+        Ok(())
+    }
+    /// Where the canvas is drawn on the surface, as xywh in physical pixels
+    pub fn surface_viewport(&self, gpu: &GPUContext) -> [f32; 4] {
+        let window = [gpu.config.width as f32, gpu.config.height as f32];
+        let canvas = [
+            self.canvas.texture.width() as f32,
+            self.canvas.texture.height() as f32,
+        ];
+        self.scale_mode.viewport(window, canvas)
+    }
+    /// Maps a physical window position to canvas pixel coordinates
+    pub fn window_to_canvas(&self, gpu: &GPUContext, x: f64, y: f64) -> [i32; 2] {
+        let window = [gpu.config.width as f32, gpu.config.height as f32];
+        let canvas = [
+            self.canvas.texture.width() as f32,
+            self.canvas.texture.height() as f32,
+        ];
+        let zoom = self.globals.scale;
+        let [cx, cy] = self
+            .scale_mode
+            .to_canvas(window, canvas, zoom, [x as f32, y as f32]);
+        [cx.floor() as i32, cy.floor() as i32]
+    }
+    /// Reads the last rendered canvas frame back from the GPU
+    pub fn capture_canvas(&self, gpu: &GPUContext) -> anyhow::Result<image::RgbaImage> {
+        crate::capture::read_texture(&gpu.device, &gpu.queue, &self.canvas.texture)
+    }
+    /// Saves the last rendered canvas frame as a timestamped PNG in `dir`
+    pub fn save_screenshot(
+        &self,
+        gpu: &GPUContext,
+        dir: &std::path::Path,
+        scale: u32,
+    ) -> anyhow::Result<std::path::PathBuf> {
+        let image = self.capture_canvas(gpu)?;
+        crate::capture::save_screenshot(&image, dir, scale)
+    }
+    /// Starts capturing every rendered canvas frame into an animation in `dir`
+    pub fn start_recording(
+        &mut self,
+        dir: &std::path::Path,
+        options: crate::capture::RecordingOptions,
+    ) {
+        if self.recorder.is_none() {
+            let recorder = crate::capture::Recorder::start(dir, self.frame_dur, options);
+            self.recorder = Some(recorder);
+        }
+    }
+    /// Stops the current recording, encoding finishes in the background
+    pub fn stop_recording(&mut self, gpu: &GPUContext) {
+        if let Some(recorder) = self.recorder.take() {
+            self.recordings.push(recorder.stop(&gpu.device));
+        }
+    }
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+    /// Collects the results of recordings that finished encoding
+    pub fn finished_recordings(&mut self) -> Vec<anyhow::Result<std::path::PathBuf>> {
+        let (finished, pending) = std::mem::take(&mut self.recordings)
+            .into_iter()
+            .partition::<Vec<_>, _>(|handle| handle.is_finished());
+        self.recordings = pending;
+        finished
+            .into_iter()
+            .map(|handle| match handle.join() {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("recording thread panicked")),
+            })
+            .collect()
+    }
+    /// Replaces the canvas with rgba pixels, the frame's quads are drawn on top
+    ///
+    /// The pixels aren't zoomed, `rgba` has to cover the whole canvas.
+    pub fn write_framebuffer(&mut self, gpu: &GPUContext, rgba: &[u8]) -> anyhow::Result<()> {
+        use wgpu::TextureFormat::*;
+        let mut pixels = rgba.to_vec();
+        match self.canvas.texture.format() {
+            Rgba8Unorm | Rgba8UnormSrgb => (),
+            Bgra8Unorm | Bgra8UnormSrgb => {
+                for px in pixels.chunks_exact_mut(4) {
+                    px.swap(0, 2);
+                }
+            }
+            format => anyhow::bail!("unsupported canvas format for framebuffers: {:?}", format),
+        }
+        self.canvas.write_pixels(&gpu.queue, &pixels);
+        self.framebuffer = true;
+        Ok(())
+    }
+    /// Creates or resizes offscreen targets to match the cart's, target n is at n - 1
+    pub fn write_targets(&mut self, gpu: &GPUContext, targets: &[TargetState]) {
+        self.targets.truncate(targets.len());
+        for (i, state) in targets.iter().enumerate() {
+            let [width, height] = state.size;
+            match self.targets.get_mut(i) {
+                Some(target) if target.canvas.texture.size() == Self::extent(state.size) => {
+                    target.clear = state.clear;
+                }
+                existing => {
+                    let target = RenderTarget {
+                        canvas: Canvas::new(
+                            &gpu.device,
+                            &gpu.queue,
+                            &gpu.config.format,
+                            width,
+                            height,
+                        ),
+                        u_globals: UniformBuffer::new(
+                            &gpu.device,
+                            Globals::new([width as f32, height as f32]),
+                        ),
+                        clear: state.clear,
+                    };
+                    match existing {
+                        Some(existing) => *existing = target,
+                        None => self.targets.push(target),
+                    }
+                }
+            }
+        }
+    }
+    fn extent([width, height]: [u32; 2]) -> wgpu::Extent3d {
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        }
+    }
+    /// Replaces the palette, takes effect from the next rendered frame
+    pub fn write_palette(&self, gpu: &GPUContext, palette: &Palette) {
+        self.u_palette
+            .write(&gpu.queue, 0, bytemuck::cast_slice(&[*palette]));
+    }
+    pub fn write_page(&self, gpu: &GPUContext, write: &PageWrite) {
+        self.spritesheet.write(&gpu.queue, write);
+    }
+    /// Scales everything drawn to the canvas, zooming in from the top-left corner
+    pub fn set_zoom(&mut self, zoom: f32) {
+        self.globals.scale = zoom;
     }
     pub fn write_uniform(&self, gpu: &GPUContext, data: &[u8]) {
         self.u_globals.write(&gpu.queue, 0, data);
     }
-    pub fn render(&mut self, gpu: &GPUContext) -> Result<instant::Instant, wgpu::SurfaceError> {
+    /// Marks a cart update at `tick` as ready, the next render draws it onto the canvas
+    pub fn set_tick(&mut self, tick: u32) {
+        self.steps += tick.saturating_sub(self.globals.tick).max(1);
+        self.globals.tick = tick;
+        self.new_frame = true;
+    }
+    /// Draws the latest cart update onto the canvas, if there is one, then presents the canvas
+    ///
+    /// Presenting doesn't depend on the cart updating, so this can run every vsync.
+    /// Returns whether a frame was presented.
+    pub fn render(&mut self, gpu: &GPUContext) -> Result<bool, wgpu::SurfaceError> {
         let now = instant::Instant::now();
-        let delta = now.sub(self.first_tick);
-        let tick = f32::floor(delta.as_secs_f32() / self.frame_dur.as_secs_f32()) as u32;
-        if tick > self.globals.tick {
-            self.globals.tick = tick;
-            self.write_uniform(gpu, &bytemuck::cast_slice(&[self.globals]));
-            if self.v_canvas.count > 0 {
-                self.render_canvas(gpu);
-                self.render_surface(gpu)?;
+        let new_frame =
+            std::mem::take(&mut self.new_frame) && (self.v_canvas.count > 0 || self.framebuffer);
+        if new_frame {
+            self.write_uniform(gpu, bytemuck::cast_slice(&[self.globals]));
+            self.write_target_globals(gpu, self.globals.tick);
+            self.render_canvas(gpu);
+            self.canvas_drawn = true;
+        }
+        if !self.canvas_drawn {
+            return Ok(false);
+        }
+        self.render_surface(gpu)?;
+        self.perf.record_encode(now.elapsed());
+        if let Some(submitted) = self.first_submit.take() {
+            if self.perf.visible {
+                self.perf.time_gpu(&gpu.queue, submitted);
+                // Native backends only report finished work when polled
+                gpu.device.poll(wgpu::Maintain::Wait);
             }
         }
-        let tick_dur = self.frame_dur.mul(tick + 1);
-        let next = self.first_tick.add(tick_dur);
-        return Ok(next);
+        self.perf.end_frame(now);
+        // Recordings get one frame per canvas update, however often it is presented,
+        // lasting as many updates as the cart ran since the last one
+        if new_frame {
+            let steps = std::mem::take(&mut self.steps);
+            self.record_frame(gpu, steps);
+        }
+        Ok(true)
+    }
+    fn record_frame(&mut self, gpu: &GPUContext, steps: u32) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        let texture = &self.canvas.texture;
+        let keep_going = match recorder.capture(&gpu.device, &gpu.queue, texture, steps) {
+            Ok(keep_going) => keep_going,
+            Err(err) => {
+                eprintln!("Error capturing recording frame: {:?}", err);
+                false
+            }
+        };
+        if !keep_going {
+            self.stop_recording(gpu);
+        }
+    }
+    fn write_target_globals(&self, gpu: &GPUContext, tick: u32) {
+        for target in &self.targets {
+            let size = target.canvas.texture.size();
+            let globals = Globals {
+                tick,
+                ..Globals::new([size.width as f32, size.height as f32])
+            };
+            target
+                .u_globals
+                .write(&gpu.queue, 0, bytemuck::cast_slice(&[globals]));
+        }
+    }
+    /// Draws the uploaded quads onto the offscreen targets only, for updates that aren't presented
+    ///
+    /// Targets keep their pixels, so what a catch-up update draws onto them
+    /// still shows up in later frames.
+    pub fn render_targets(&mut self, gpu: &GPUContext, tick: u32) {
+        self.write_target_globals(gpu, tick);
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Grainboy Target Render Encoder"),
+            });
+        self.encode_targets(&mut encoder);
+        self.submit(gpu, encoder.finish());
+    }
+    /// Submits GPU work, noting when the frame's first work went in for the HUD's GPU time
+    fn submit(&mut self, gpu: &GPUContext, cmd_buf: wgpu::CommandBuffer) {
+        self.first_submit.get_or_insert_with(instant::Instant::now);
+        gpu.queue.submit(std::iter::once(cmd_buf));
     }
     fn render_canvas(&mut self, gpu: &GPUContext) {
         let mut encoder = gpu
@@ -130,25 +434,54 @@ impl<'a> Renderer<'a> {
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Grainboy Canvas Render Encoder"),
             });
+        // Offscreen targets go first so the canvas can draw what was drawn onto them
+        self.encode_targets(&mut encoder);
+        let load = match std::mem::take(&mut self.framebuffer) {
+            true => wgpu::LoadOp::Load,
+            false => wgpu::LoadOp::Clear(self.clear_color),
+        };
+        let zoom = self.globals.scale;
+        self.encode_pass(&mut encoder, 0, &self.canvas, &self.u_globals, load, zoom);
+        self.submit(gpu, encoder.finish());
+    }
+    fn encode_targets(&self, encoder: &mut wgpu::CommandEncoder) {
+        for (i, target) in self.targets.iter().enumerate() {
+            let id = i as u32 + 1;
+            let load = match target.clear {
+                Some(color) => wgpu::LoadOp::Clear(color_from_rgba(color)),
+                // Targets keep their pixels, so skip the ones nothing is drawn onto
+                None if !self.batches.iter().any(|batch| batch.target == id) => continue,
+                None => wgpu::LoadOp::Load,
+            };
+            self.encode_pass(encoder, id, &target.canvas, &target.u_globals, load, 1.);
+        }
+    }
+    /// Draws the batches that go onto `target`, 0 being the canvas
+    fn encode_pass(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: u32,
+        canvas: &Canvas,
+        u_globals: &UniformBuffer<Globals>,
+        load: wgpu::LoadOp<wgpu::Color>,
+        zoom: f32,
+    ) {
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Grainboy Canvas Render Pass"),
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &self.canvas.view,
+                view: &canvas.view,
                 resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(self.clear_color),
-                    store: true,
-                },
+                ops: wgpu::Operations { load, store: true },
             })],
             depth_stencil_attachment: None,
         });
-        let w = self.canvas.texture.width();
-        let h = self.canvas.texture.height();
+        let w = canvas.texture.width();
+        let h = canvas.texture.height();
         render_pass.set_viewport(0., 0., w as f32, h as f32, 0.0, 1.0);
         render_pass.set_scissor_rect(0, 0, w, h);
         render_pass.set_pipeline(&self.pipeline);
-        render_pass.set_bind_group(0, &self.u_globals.bind_group, &[]);
-        render_pass.set_bind_group(1, &self.spritesheet.bind_group, &[]);
+        render_pass.set_bind_group(0, &u_globals.bind_group, &[]);
+        render_pass.set_bind_group(2, &self.u_palette.bind_group, &[]);
         let vxs = &self.v_canvas;
         let num_quads = vxs.count as u64;
         render_pass.set_vertex_buffer(0, {
@@ -158,18 +491,33 @@ impl<'a> Renderer<'a> {
             let bounds = 0..limit;
             vxs.quads.slice(bounds)
         });
-        render_pass.set_vertex_buffer(1, {
-            let size = std::mem::size_of::<IndexVertex>() as u64;
-            let count = num_quads * 6;
-            let limit = size * count;
-            let bounds = 0..limit;
-            vxs.indices.slice(bounds)
-        });
-        let num_quads = num_quads as u32;
-        render_pass.draw(0..(num_quads * 6), 0..num_quads);
-        drop(render_pass);
-        let cmd_buf = encoder.finish();
-        gpu.queue.submit(std::iter::once(cmd_buf));
+        for batch in self.batches.iter().filter(|batch| batch.target == target) {
+            let source = match batch.source {
+                0 => &self.spritesheet.bind_group,
+                // A target can't be sampled while it's being drawn onto
+                source if source == target => continue,
+                source => match self.targets.get(source as usize - 1) {
+                    Some(source) => &source.canvas.bind_group,
+                    None => continue,
+                },
+            };
+            render_pass.set_bind_group(1, source, &[]);
+            match batch.clip {
+                Some([cx, cy, cw, ch]) => {
+                    // Clip rects are in cart coordinates, so follow the zoom
+                    let x0 = (cx * zoom).floor().clamp(0., w as f32) as u32;
+                    let y0 = (cy * zoom).floor().clamp(0., h as f32) as u32;
+                    let x1 = ((cx + cw) * zoom).ceil().clamp(0., w as f32) as u32;
+                    let y1 = ((cy + ch) * zoom).ceil().clamp(0., h as f32) as u32;
+                    if x1 <= x0 || y1 <= y0 {
+                        continue;
+                    }
+                    render_pass.set_scissor_rect(x0, y0, x1 - x0, y1 - y0);
+                }
+                None => render_pass.set_scissor_rect(0, 0, w, h),
+            }
+            render_pass.draw(0..6, batch.quads.clone());
+        }
     }
     fn render_surface(&mut self, gpu: &GPUContext) -> Result<(), wgpu::SurfaceError> {
         let mut encoder = gpu
@@ -177,6 +525,25 @@ impl<'a> Renderer<'a> {
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Grainboy Surface Render Encoder"),
             });
+        let [vx, vy, vw, vh] = self.surface_viewport(gpu);
+        if self.post_effects.is_enabled() {
+            let source_size = [
+                self.canvas.texture.width() as f32,
+                self.canvas.texture.height() as f32,
+            ];
+            let uniforms = self.post_effects.uniforms(source_size, [vw, vh]);
+            self.post.write(&gpu.queue, uniforms);
+        }
+        // The HUD goes over the canvas here, so it stays out of screenshots and recordings
+        let hud = match self.perf.visible {
+            true => self.perf.hud_quads(),
+            false => vec![],
+        };
+        if !hud.is_empty() {
+            let device_max = VertexBuffer::device_max(&gpu.device);
+            self.v_hud.reserve(&gpu.device, hud.len(), device_max);
+            self.v_hud.write(&gpu.queue, 0, bytemuck::cast_slice(&hud));
+        }
         let frame = gpu.surface.get_current_texture()?;
         let view = frame.texture.create_view(&wgpu::TextureViewDescriptor {
             ..Default::default()
@@ -198,53 +565,121 @@ impl<'a> Renderer<'a> {
             })],
             depth_stencil_attachment: None,
         });
-        let ww = gpu.config.width as f32;
-        let wh = gpu.config.height as f32;
-        let cw = self.canvas.texture.width() as f32;
-        let ch = self.canvas.texture.height() as f32;
-        let aspect_ratio = cw / ch;
-        let (vw, vh) = if ww <= wh * aspect_ratio {
-            let vw = ww;
-            let vh = vw / aspect_ratio;
-            (vw, vh)
-        } else {
-            let vh = wh;
-            let vw = vh * aspect_ratio;
-            (vw, vh)
-        };
-        let vx = (ww - vw) / 2.;
-        let vy = (wh - vh) / 2.;
         render_pass.set_viewport(vx, vy, vw, vh, 1., 1.);
         render_pass.set_scissor_rect(vx as u32, vy as u32, vw as u32, vh as u32);
-        render_pass.set_pipeline(&self.pipeline);
-        render_pass.set_bind_group(0, &self.u_globals.bind_group, &[]);
-        render_pass.set_bind_group(1, &self.canvas.bind_group, &[]);
-        let vxs = &self.v_surface;
-        let num_quads = vxs.count as u64;
-        render_pass.set_vertex_buffer(0, {
-            let size = std::mem::size_of::<QuadVertex>() as u64;
-            let count = num_quads;
-            let limit = size * count;
-            let bounds = 0..limit;
-            vxs.quads.slice(bounds)
-        });
-        render_pass.set_vertex_buffer(1, {
-            let size = std::mem::size_of::<IndexVertex>() as u64;
-            let count = num_quads * 6;
-            let limit = size * count;
-            let bounds = 0..limit;
-            vxs.indices.slice(bounds)
-        });
-        let num_quads = num_quads as u32;
-        render_pass.draw(0..(num_quads * 6), 0..num_quads);
+        if self.post_effects.is_enabled() {
+            render_pass.set_pipeline(&self.post.pipeline);
+            render_pass.set_bind_group(0, &self.post.uniforms.bind_group, &[]);
+            render_pass.set_bind_group(1, &self.canvas.bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        } else {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.u_surface_globals.bind_group, &[]);
+            render_pass.set_bind_group(1, &self.canvas.bind_group, &[]);
+            render_pass.set_bind_group(2, &self.u_palette.bind_group, &[]);
+            let vxs = &self.v_surface;
+            let num_quads = vxs.count as u64;
+            render_pass.set_vertex_buffer(0, {
+                let size = std::mem::size_of::<QuadVertex>() as u64;
+                let count = num_quads;
+                let limit = size * count;
+                let bounds = 0..limit;
+                vxs.quads.slice(bounds)
+            });
+            let num_quads = num_quads as u32;
+            render_pass.draw(0..6, 0..num_quads);
+        }
+        if !hud.is_empty() {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.u_surface_globals.bind_group, &[]);
+            render_pass.set_bind_group(1, &self.hud_fonts.bind_group, &[]);
+            render_pass.set_bind_group(2, &self.u_palette.bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.v_hud.quads.slice(..));
+            render_pass.draw(0..6, 0..hud.len() as u32);
+        }
         drop(render_pass);
-        let cmd_buf = encoder.finish();
-        gpu.queue.submit(std::iter::once(cmd_buf));
+        self.submit(gpu, encoder.finish());
         frame.present();
         Ok(())
     }
 }
 
+/// How the canvas is scaled to fit the window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScaleMode {
+    /// Largest whole-number scale that fits, so every pixel is the same size
+    Integer,
+    /// Largest scale that fits while keeping the aspect ratio (letterboxed)
+    AspectFit,
+    /// Fills the whole window, ignoring the aspect ratio
+    Stretch,
+}
+impl ScaleMode {
+    pub fn next(self) -> Self {
+        match self {
+            ScaleMode::Integer => ScaleMode::AspectFit,
+            ScaleMode::AspectFit => ScaleMode::Stretch,
+            ScaleMode::Stretch => ScaleMode::Integer,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            ScaleMode::Integer => "integer",
+            ScaleMode::AspectFit => "aspect-fit",
+            ScaleMode::Stretch => "stretch",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "integer" => Some(ScaleMode::Integer),
+            "aspect-fit" => Some(ScaleMode::AspectFit),
+            "stretch" => Some(ScaleMode::Stretch),
+            _ => None,
+        }
+    }
+    /// Computes the xywh viewport of a canvas inside a window
+    pub fn viewport(self, window: [f32; 2], canvas: [f32; 2]) -> [f32; 4] {
+        let [ww, wh] = window;
+        let [cw, ch] = canvas;
+        let (vw, vh) = match self {
+            ScaleMode::Stretch => (ww, wh),
+            ScaleMode::Integer if ww >= cw && wh >= ch => {
+                let scale = f32::min(ww / cw, wh / ch).floor();
+                (cw * scale, ch * scale)
+            }
+            // Windows smaller than the canvas can't be integer scaled, so fall back to fitting
+            ScaleMode::Integer | ScaleMode::AspectFit => {
+                let aspect_ratio = cw / ch;
+                if ww <= wh * aspect_ratio {
+                    let vw = ww;
+                    let vh = vw / aspect_ratio;
+                    (vw, vh)
+                } else {
+                    let vh = wh;
+                    let vw = vh * aspect_ratio;
+                    (vw, vh)
+                }
+            }
+        };
+        let vx = ((ww - vw) / 2.).floor();
+        let vy = ((wh - vh) / 2.).floor();
+        [vx, vy, vw, vh]
+    }
+    /// Maps a window position to the coordinates carts draw in, which `zoom` scales
+    pub fn to_canvas(
+        self,
+        window: [f32; 2],
+        canvas: [f32; 2],
+        zoom: f32,
+        pos: [f32; 2],
+    ) -> [f32; 2] {
+        let [vx, vy, vw, vh] = self.viewport(window, canvas);
+        let [cw, ch] = canvas;
+        let [x, y] = pos;
+        [(x - vx) * cw / vw / zoom, (y - vy) * ch / vh / zoom]
+    }
+}
+
 #[derive(Debug)]
 pub struct GPUContext {
     pub window: winit::window::Window,
@@ -292,7 +727,7 @@ impl GPUContext {
         let capabilities = surface.get_capabilities(&adapter);
         let default_format = *capabilities
             .formats
-            .get(0)
+            .first()
             .expect("No surface formats available");
         let surface_format = capabilities
             .formats
@@ -302,11 +737,11 @@ impl GPUContext {
             .unwrap_or(default_format);
         let present_mode = *capabilities
             .present_modes
-            .get(0)
+            .first()
             .expect("No surface present modes available");
         let default_alpha_mode = *capabilities
             .alpha_modes
-            .get(0)
+            .first()
             .expect("No surface alpha modes available");
         let alpha_mode = capabilities
             .alpha_modes
@@ -336,6 +771,14 @@ impl GPUContext {
             queue,
         }
     }
+    /// Waits for vblank when presenting, otherwise presents as soon as a frame is ready
+    pub fn set_vsync(&mut self, vsync: bool) {
+        self.config.present_mode = match vsync {
+            true => wgpu::PresentMode::AutoVsync,
+            false => wgpu::PresentMode::AutoNoVsync,
+        };
+        self.surface.configure(&self.device, &self.config);
+    }
 }
 
 #[repr(C, packed)]
@@ -355,6 +798,46 @@ impl Globals {
         }
     }
 }
+/// Color table that quads can index into instead of using raw rgba colors
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Palette {
+    pub colors: [u32; 256],
+}
+impl Palette {
+    /// PICO-8's 16 colors, the remaining entries are transparent
+    pub const DEFAULT: Self = {
+        const fn rgb(r: u32, g: u32, b: u32) -> u32 {
+            0xff000000 | (b << 16) | (g << 8) | r
+        }
+        let mut colors = [0; 256];
+        let default = [
+            rgb(0x00, 0x00, 0x00),
+            rgb(0x1d, 0x2b, 0x53),
+            rgb(0x7e, 0x25, 0x53),
+            rgb(0x00, 0x87, 0x51),
+            rgb(0xab, 0x52, 0x36),
+            rgb(0x5f, 0x57, 0x4f),
+            rgb(0xc2, 0xc3, 0xc7),
+            rgb(0xff, 0xf1, 0xe8),
+            rgb(0xff, 0x00, 0x4d),
+            rgb(0xff, 0xa3, 0x00),
+            rgb(0xff, 0xec, 0x27),
+            rgb(0x00, 0xe4, 0x36),
+            rgb(0x29, 0xad, 0xff),
+            rgb(0x83, 0x76, 0x9c),
+            rgb(0xff, 0x77, 0xa8),
+            rgb(0xff, 0xcc, 0xaa),
+        ];
+        let mut i = 0;
+        while i < default.len() {
+            colors[i] = default[i];
+            i += 1;
+        }
+        Self { colors }
+    };
+}
+
 #[derive(Debug)]
 pub struct UniformBuffer<T> {
     pub uniform: T,
@@ -381,7 +864,7 @@ impl<T: bytemuck::Pod + bytemuck::Zeroable> UniformBuffer<T> {
                     // min_binding_size: None,
                     min_binding_size: wgpu::BufferSize::new(
                         // Must have a size that is a multiple of 16 bytes
-                        std::mem::size_of::<Globals>() as u64,
+                        std::mem::size_of::<T>() as u64,
                     ),
                 },
                 count: None,
@@ -408,12 +891,6 @@ impl<T: bytemuck::Pod + bytemuck::Zeroable> UniformBuffer<T> {
     }
 }
 
-#[repr(C, packed)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct IndexVertex {
-    pub index: u32,
-}
-
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct QuadVertex {
@@ -427,9 +904,15 @@ pub struct QuadVertex {
     pub border_radius: [u32; 2],
     pub border_size: u32,
     pub border_color: [u32; 4],
+    pub palette: u32, // which colors are palette indices (see PALETTE_*)
+    pub tint: u32,    // rgba multiplied with the fill/texture color, never a palette index
+    pub page: u32,    // spritesheet page that tex_rect refers to
 }
 impl QuadVertex {
-    pub const ATTRIBUTE_ARRAY: [wgpu::VertexAttribute; 10] = wgpu::vertex_attr_array![
+    pub const PALETTE_FILL: u32 = 1;
+    pub const PALETTE_TEX_FILL: u32 = 2;
+    pub const PALETTE_BORDER_COLOR: u32 = 4;
+    pub const ATTRIBUTE_ARRAY: [wgpu::VertexAttribute; 13] = wgpu::vertex_attr_array![
         1 => Float32x4,  // rect
         2 => Uint32,     // fill
         3 => Float32x4,  // tex_rect
@@ -440,6 +923,9 @@ impl QuadVertex {
         8 => Uint32x2,   // border_radius
         9 => Uint32,     // border_size (top, right, bottom left)
         10 => Uint32x4,  // border_color (top, right, bottom left)
+        11 => Uint32,    // palette
+        12 => Uint32,    // tint
+        13 => Uint32,    // page
     ];
     pub const fn new(rect: [f32; 4]) -> Self {
         Self {
@@ -449,15 +935,35 @@ impl QuadVertex {
             tex_fill: 0,
             rotation_base: 0.,
             rotation_rate: 0.,
-            rotation_origin: [0.; 2],
+            // Rotate around the center unless told otherwise
+            rotation_origin: [rect[2] / 2., rect[3] / 2.],
             border_radius: [0; 2],
             border_size: 0,
             border_color: [0; 4],
+            palette: 0,
+            tint: 0xffffffff,
+            page: Spritesheet::FONT_PAGE,
         }
     }
     pub const fn tex_rect(&self, tex_rect: [f32; 4]) -> Self {
         Self { tex_rect, ..*self }
     }
+    /// Mirrors the texture by walking `tex_rect` backwards
+    pub const fn flip(&self, x: bool, y: bool) -> Self {
+        let [mut tx, mut ty, mut tw, mut th] = self.tex_rect;
+        if x {
+            tx += tw;
+            tw = -tw;
+        }
+        if y {
+            ty += th;
+            th = -th;
+        }
+        Self {
+            tex_rect: [tx, ty, tw, th],
+            ..*self
+        }
+    }
     pub const fn border_radius(&self, border_radius: [(u32, u32); 4]) -> Self {
         let [tl, tr, br, bl] = border_radius;
         let xs = (bl.0 << 24) | (br.0 << 16) | (tr.0 << 8) | tl.0;
@@ -467,66 +973,179 @@ impl QuadVertex {
             ..*self
         }
     }
+    /// Border sizes and colors, both ordered top, right, bottom, left
+    pub const fn border(&self, border_size: [u32; 4], border_color: [u32; 4]) -> Self {
+        let [t, r, b, l] = border_size;
+        Self {
+            border_size: (l << 24) | (b << 16) | (r << 8) | t,
+            border_color,
+            ..*self
+        }
+    }
+}
+
+/// A range of quads drawn with the same clip rectangle, target and texture
+#[derive(Clone, Debug, PartialEq)]
+pub struct DrawBatch {
+    pub quads: std::ops::Range<u32>,
+    pub clip: Option<[f32; 4]>, // xywh in cart coordinates
+    /// 0 draws onto the canvas, n onto offscreen target n
+    pub target: u32,
+    /// 0 samples the spritesheet, n samples offscreen target n
+    pub source: u32,
+}
+
+/// What a cart asked of an offscreen target this frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TargetState {
+    pub size: [u32; 2],
+    /// Cleared to this 0xAABBGGRR color before anything is drawn onto it
+    pub clear: Option<u32>,
+}
+
+/// An offscreen canvas that carts draw into and then draw as a sprite
+#[derive(Debug)]
+struct RenderTarget {
+    canvas: Canvas,
+    /// The target's own viewport, offscreen targets aren't zoomed
+    u_globals: UniformBuffer<Globals>,
+    clear: Option<u32>,
+}
+
+/// Translation, uniform scale and rotation applied to quads as they're drawn
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    pub translate: [f32; 2],
+    pub scale: f32,
+    pub rotation: f32, // radians
+}
+impl Transform {
+    pub const IDENTITY: Self = Self {
+        translate: [0.; 2],
+        scale: 1.,
+        rotation: 0.,
+    };
+    pub fn apply(&self, [x, y]: [f32; 2]) -> [f32; 2] {
+        let (sin, cos) = self.rotation.sin_cos();
+        let x = x * self.scale;
+        let y = y * self.scale;
+        [
+            x * cos - y * sin + self.translate[0],
+            x * sin + y * cos + self.translate[1],
+        ]
+    }
+    /// Undoes `self`, the scale has to be non-zero
+    pub fn inverse(&self) -> Self {
+        let mut inverse = Self {
+            translate: [0.; 2],
+            scale: 1. / self.scale,
+            rotation: -self.rotation,
+        };
+        let [tx, ty] = inverse.apply(self.translate);
+        inverse.translate = [-tx, -ty];
+        inverse
+    }
+    /// Returns a transform that applies `local` first and then `self`
+    pub fn then(&self, local: &Transform) -> Self {
+        Self {
+            translate: self.apply(local.translate),
+            scale: self.scale * local.scale,
+            rotation: self.rotation + local.rotation,
+        }
+    }
+    /// Moves, scales and rotates a quad around its rotation origin
+    ///
+    /// The shader rotates quads around their rotation origin, so moving that
+    /// point and adding to the quad's own rotation is enough.
+    pub fn apply_to_quad(&self, quad: &QuadVertex) -> QuadVertex {
+        if *self == Self::IDENTITY {
+            return *quad;
+        }
+        let [x, y, w, h] = quad.rect;
+        let [ox, oy] = quad.rotation_origin;
+        let [px, py] = self.apply([x + ox, y + oy]);
+        let [ox, oy] = [ox * self.scale, oy * self.scale];
+        let w = w * self.scale;
+        let h = h * self.scale;
+        let scale_bytes = |packed: u32| -> u32 {
+            packed
+                .to_le_bytes()
+                .iter()
+                .enumerate()
+                .map(|(i, &b)| ((b as f32 * self.scale).round().min(255.) as u32) << (i * 8))
+                .sum()
+        };
+        let [brx, bry] = quad.border_radius;
+        QuadVertex {
+            rect: [px - ox, py - oy, w, h],
+            rotation_origin: [ox, oy],
+            rotation_base: quad.rotation_base + self.rotation,
+            border_radius: [scale_bytes(brx), scale_bytes(bry)],
+            border_size: scale_bytes(quad.border_size),
+            ..*quad
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct VertexBuffer<'a> {
     pub quads: wgpu::Buffer,
-    pub indices: wgpu::Buffer,
     pub count: usize,
+    /// How many quads `quads` has room for
+    pub capacity: usize,
     pub layouts: &'a [wgpu::VertexBufferLayout<'a>],
 }
 impl<'a> VertexBuffer<'a> {
-    pub fn new(device: &wgpu::Device) -> Self {
-        use wgpu::util::DeviceExt;
-        // Gotta be careful about device.limits.maxBufferSize
-        const MAX_QUADS: usize = 1_000_000;
-        let quads = {
-            let contents = &[0; MAX_QUADS * std::mem::size_of::<QuadVertex>()];
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Grainboy Quad Vertex Buffer"),
-                contents,
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            })
-        };
-        let indices = {
-            let contents: Vec<IndexVertex> = (0..MAX_QUADS * 6)
-                .enumerate()
-                .map(|(i, _)| IndexVertex {
-                    index: i as u32 % 6,
-                })
-                .collect();
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Grainboy Index Vertex Buffer"),
-                contents: bytemuck::cast_slice(&contents),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            })
-        };
+    /// Quads the canvas buffer has room for before it first grows
+    pub const INITIAL_CAPACITY: usize = 1024;
+    /// Default for `Renderer::max_quads`
+    pub const DEFAULT_MAX_QUADS: usize = 1_000_000;
+    pub fn new(device: &wgpu::Device, capacity: usize) -> Self {
+        // Every quad is an instance of the same 6 vertices, whose corners the
+        // shader derives from the vertex index
+        let quads = Self::create_quad_buffer(device, capacity);
         Self {
             quads,
-            indices,
             count: 0,
-            layouts: &[
-                wgpu::VertexBufferLayout {
-                    array_stride: std::mem::size_of::<QuadVertex>() as wgpu::BufferAddress,
-                    step_mode: wgpu::VertexStepMode::Instance,
-                    attributes: &QuadVertex::ATTRIBUTE_ARRAY,
-                },
-                wgpu::VertexBufferLayout {
-                    array_stride: std::mem::size_of::<IndexVertex>() as wgpu::BufferAddress,
-                    step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &[wgpu::VertexAttribute {
-                        format: wgpu::VertexFormat::Uint32,
-                        offset: std::mem::size_of::<QuadVertex>() as u64,
-                        shader_location: 0,
-                    }],
-                },
-            ],
+            capacity,
+            layouts: &[wgpu::VertexBufferLayout {
+                array_stride: std::mem::size_of::<QuadVertex>() as wgpu::BufferAddress,
+                step_mode: wgpu::VertexStepMode::Instance,
+                attributes: &QuadVertex::ATTRIBUTE_ARRAY,
+            }],
+        }
+    }
+    fn create_quad_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Grainboy Quad Vertex Buffer"),
+            size: (capacity.max(1) * std::mem::size_of::<QuadVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+    /// Most quads one buffer can hold on `device`
+    pub fn device_max(device: &wgpu::Device) -> usize {
+        let size = std::mem::size_of::<QuadVertex>() as u64;
+        (device.limits().max_buffer_size / size) as usize
+    }
+    /// Makes room for `quads` quads, doubling the capacity when it grows but
+    /// never past `limit`, unless `quads` itself is larger
+    ///
+    /// Growing replaces the buffer, so anything written before is lost.
+    pub fn reserve(&mut self, device: &wgpu::Device, quads: usize, limit: usize) {
+        if quads > self.capacity {
+            self.capacity = (self.capacity * 2).min(limit).max(quads);
+            self.quads = Self::create_quad_buffer(device, self.capacity);
+            self.count = 0;
         }
     }
     pub fn write(&mut self, queue: &wgpu::Queue, offset: wgpu::BufferAddress, data: &[u8]) {
+        debug_assert!(
+            offset as usize + data.len() <= self.capacity * std::mem::size_of::<QuadVertex>(),
+            "VertexBuffer::write past its capacity, call reserve first"
+        );
         queue.write_buffer(&self.quads, offset, data);
-        self.count = data.len() / std::mem::size_of::<QuadVertex>();
+        self.count = (offset as usize + data.len()) / std::mem::size_of::<QuadVertex>();
     }
 }
 
@@ -539,6 +1158,8 @@ pub struct Canvas {
     pub vertex_bytes: Vec<u8>,
 }
 impl Canvas {
+    /// Size of the canvas carts draw on, before zooming
+    pub const SIZE: [u32; 2] = [256, 144];
     pub fn new(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
@@ -564,27 +1185,8 @@ impl Canvas {
                 | wgpu::TextureUsages::COPY_SRC,
             view_formats: &[],
         });
-        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("Grainboy Canvas Texture BindGroupLayout"),
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-            ],
-        });
+        // Bound like a one-page spritesheet so the same pipeline can draw it
+        let layout = texture_array_layout(device, "Grainboy Canvas Texture BindGroupLayout");
         let view = texture.create_view(&wgpu::TextureViewDescriptor {
             ..Default::default()
         });
@@ -594,7 +1196,12 @@ impl Canvas {
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&view),
+                    resource: wgpu::BindingResource::TextureView(&texture.create_view(
+                        &wgpu::TextureViewDescriptor {
+                            dimension: Some(wgpu::TextureViewDimension::D2Array),
+                            ..Default::default()
+                        },
+                    )),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
@@ -612,14 +1219,29 @@ impl Canvas {
                 },
             ],
         });
+        let tex_size = [0., 0., size.width as f32, size.height as f32];
+        let quad = QuadVertex::new(tex_size).tex_rect(tex_size);
+        let canvas = Self {
+            texture,
+            view,
+            layout,
+            bind_group,
+            vertex_bytes: bytemuck::cast_slice(&[quad]).to_vec(),
+        };
+        canvas.write_pixels(queue, &vec![0; (size.width * size.height * 4) as usize]);
+        canvas
+    }
+    /// Overwrites the whole canvas, `pixels` has to be in the canvas' format
+    pub fn write_pixels(&self, queue: &wgpu::Queue, pixels: &[u8]) {
+        let size = self.texture.size();
         queue.write_texture(
             wgpu::ImageCopyTexture {
-                texture: &texture,
+                texture: &self.texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
                 aspect: wgpu::TextureAspect::All,
             },
-            &vec![0; (size.width * size.height * 4) as usize],
+            pixels,
             wgpu::ImageDataLayout {
                 offset: 0,
                 bytes_per_row: Some(
@@ -635,18 +1257,147 @@ impl Canvas {
             },
             size,
         );
-        let tex_size = [0., 0., size.width as f32, size.height as f32];
-        let quad = QuadVertex::new(tex_size).tex_rect(tex_size);
-        Self {
-            texture,
-            view,
-            layout,
-            bind_group,
-            vertex_bytes: bytemuck::cast_slice(&[quad]).to_vec(),
+    }
+}
+
+/// Converts a 0xAABBGGRR color for clearing a render target
+fn color_from_rgba(color: u32) -> wgpu::Color {
+    let [r, g, b, a] = color.to_le_bytes().map(|c| c as f64 / 255.);
+    wgpu::Color { r, g, b, a }
+}
+
+/// Layout for a texture array and its sampler, as read by the shaders
+fn texture_array_layout(device: &wgpu::Device, label: &str) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ],
+    })
+}
+
+/// A pending change to a spritesheet page
+#[derive(Clone, Debug)]
+pub enum PageWrite {
+    /// Brings back the page's built-in contents
+    Restore(u32),
+    /// Replaces the whole page, anything the image doesn't cover is cleared
+    Replace(u32, image::RgbaImage),
+    /// Overwrites part of a page
+    Region {
+        page: u32,
+        origin: [u32; 2],
+        image: image::RgbaImage,
+    },
+}
+
+/// CPU copy of the spritesheet pages, so carts can read and write single pixels
+///
+/// Every `PageWrite` has to go through `apply` to keep the copy in sync. Pixel
+/// edits are collected into a dirty rect per page and turned into one
+/// `PageWrite::Region` per page by `flush`, once per frame.
+#[derive(Debug, Default)]
+pub struct SpritePixels {
+    /// Pages that aren't here still have their built-in contents
+    pages: std::collections::HashMap<u32, image::RgbaImage>,
+    /// x0, y0, x1, y1 of the pixels edited since the last flush
+    dirty: std::collections::HashMap<u32, [u32; 4]>,
+}
+impl SpritePixels {
+    pub fn apply(&mut self, write: &PageWrite) {
+        match write {
+            PageWrite::Restore(page) => {
+                self.pages.remove(page);
+            }
+            PageWrite::Replace(page, image) => {
+                let pixels = Spritesheet::page_pixels(*page, Some(image));
+                self.pages.insert(*page, pixels);
+            }
+            PageWrite::Region {
+                page,
+                origin: [x, y],
+                image,
+            } => {
+                let pixels = self.page_mut(*page);
+                image::imageops::replace(pixels, image, *x as i64, *y as i64);
+            }
+        }
+    }
+    fn page_mut(&mut self, page: u32) -> &mut image::RgbaImage {
+        self.pages
+            .entry(page)
+            .or_insert_with(|| Spritesheet::page_pixels(page, None))
+    }
+    /// The 0xAABBGGRR color of a pixel, transparent outside of the page
+    pub fn get(&mut self, page: u32, x: u32, y: u32) -> u32 {
+        match self.page_mut(page).get_pixel_checked(x, y) {
+            Some(pixel) => u32::from_le_bytes(pixel.0),
+            None => 0,
+        }
+    }
+    /// Sets a pixel to a 0xAABBGGRR color, pixels outside of the page are ignored
+    pub fn set(&mut self, page: u32, x: u32, y: u32, color: u32) {
+        let Some(pixel) = self.page_mut(page).get_pixel_mut_checked(x, y) else {
+            return;
+        };
+        pixel.0 = color.to_le_bytes();
+        self.mark_dirty(page, [x, y, x + 1, y + 1]);
+    }
+    /// Copies `image` onto a page, whatever falls outside of the page is ignored
+    pub fn blit(&mut self, page: u32, [x, y]: [u32; 2], image: &image::RgbaImage) {
+        let pixels = self.page_mut(page);
+        image::imageops::replace(pixels, image, x as i64, y as i64);
+        let x1 = x.saturating_add(image.width()).min(pixels.width());
+        let y1 = y.saturating_add(image.height()).min(pixels.height());
+        if x < x1 && y < y1 {
+            self.mark_dirty(page, [x, y, x1, y1]);
+        }
+    }
+    fn mark_dirty(&mut self, page: u32, [x0, y0, x1, y1]: [u32; 4]) {
+        let rect = self.dirty.entry(page).or_insert([x0, y0, x1, y1]);
+        *rect = [
+            rect[0].min(x0),
+            rect[1].min(y0),
+            rect[2].max(x1),
+            rect[3].max(y1),
+        ];
+    }
+    /// The edited part of every page since the last flush
+    pub fn flush(&mut self) -> Vec<PageWrite> {
+        let mut writes = vec![];
+        for (page, [x0, y0, x1, y1]) in self.dirty.drain() {
+            if let Some(pixels) = self.pages.get(&page) {
+                let image = image::imageops::crop_imm(pixels, x0, y0, x1 - x0, y1 - y0);
+                writes.push(PageWrite::Region {
+                    page,
+                    origin: [x0, y0],
+                    image: image.to_image(),
+                });
+            }
         }
+        writes
     }
 }
 
+/// Fixed-size texture pages that quads pick with `QuadVertex::page`
+///
+/// Page 0 holds the built-in fonts and page 1 the built-in sprites, carts can
+/// replace any page at runtime.
 #[derive(Debug)]
 pub struct Spritesheet {
     pub texture: wgpu::Texture,
@@ -655,17 +1406,27 @@ pub struct Spritesheet {
 }
 impl Spritesheet {
     const BYTES: &'static [u8] = include_bytes!("spritesheet.png");
-    const EXTENT: wgpu::Extent3d = wgpu::Extent3d {
+    pub const PAGE_EXTENT: wgpu::Extent3d = wgpu::Extent3d {
         width: 256,
         height: 1280,
         depth_or_array_layers: 1,
     };
+    pub const PAGES: u32 = 8;
+    pub const FONT_PAGE: u32 = 0;
+    pub const SPRITE_PAGE: u32 = 1;
+    /// Rows of `spritesheet.png` taken up by the fonts, the sprites follow
+    pub const FONT_ROWS: u32 = 128;
     pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
-        let image = image::load_from_memory(Self::BYTES).unwrap();
-        let rgba = image.to_rgba8();
+        Self::with_pages(device, queue, Self::PAGES)
+    }
+    /// Only the first `pages` pages, with their built-in contents
+    pub fn with_pages(device: &wgpu::Device, queue: &wgpu::Queue, pages: u32) -> Self {
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: Some("Grainboy Spritesheet Texture"),
-            size: Self::EXTENT,
+            size: wgpu::Extent3d {
+                depth_or_array_layers: pages,
+                ..Self::PAGE_EXTENT
+            },
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
@@ -673,36 +1434,19 @@ impl Spritesheet {
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             view_formats: &[],
         });
-        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("Grainboy Spritesheet Texture BindGroupLayout"),
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-            ],
-        });
+        let layout = texture_array_layout(device, "Grainboy Spritesheet Texture BindGroupLayout");
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Grainboy Spritesheet Texture BindGroup"),
             layout: &layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(
-                        &texture.create_view(&wgpu::TextureViewDescriptor::default()),
-                    ),
+                    resource: wgpu::BindingResource::TextureView(&texture.create_view(
+                        &wgpu::TextureViewDescriptor {
+                            dimension: Some(wgpu::TextureViewDimension::D2Array),
+                            ..Default::default()
+                        },
+                    )),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
@@ -720,33 +1464,151 @@ impl Spritesheet {
                 },
             ],
         });
+        let spritesheet = Self {
+            texture,
+            layout,
+            bind_group,
+        };
+        for page in 0..pages {
+            spritesheet.write_page(queue, page, None);
+        }
+        spritesheet
+    }
+    pub fn write(&self, queue: &wgpu::Queue, write: &PageWrite) {
+        match write {
+            PageWrite::Restore(page) => self.write_page(queue, *page, None),
+            PageWrite::Replace(page, image) => self.write_page(queue, *page, Some(image)),
+            PageWrite::Region {
+                page,
+                origin,
+                image,
+            } => self.write_region(queue, *page, *origin, image),
+        }
+    }
+    /// `spritesheet.png`, the built-in fonts followed by the built-in sprites
+    pub fn builtin() -> &'static image::RgbaImage {
+        static BUILTIN: std::sync::OnceLock<image::RgbaImage> = std::sync::OnceLock::new();
+        BUILTIN.get_or_init(|| image::load_from_memory(Self::BYTES).unwrap().to_rgba8())
+    }
+    /// A full page with `image` in the top-left corner, `None` gives the built-in contents
+    pub fn page_pixels(page: u32, image: Option<&image::RgbaImage>) -> image::RgbaImage {
+        use image::GenericImageView;
+        let wgpu::Extent3d { width, height, .. } = Self::PAGE_EXTENT;
+        let image = match image {
+            Some(image) => image.view(0, 0, image.width().min(width), image.height().min(height)),
+            None => {
+                let builtin = Self::builtin();
+                let (y, rows) = match page {
+                    Self::FONT_PAGE => (0, Self::FONT_ROWS),
+                    Self::SPRITE_PAGE => (Self::FONT_ROWS, builtin.height() - Self::FONT_ROWS),
+                    _ => (0, 0),
+                };
+                builtin.view(0, y, builtin.width(), rows)
+            }
+        };
+        let mut pixels = image::RgbaImage::new(width, height);
+        image::imageops::replace(&mut pixels, &*image, 0, 0);
+        pixels
+    }
+    /// Replaces a page, anything `image` doesn't cover is cleared
+    ///
+    /// Passing `None` restores the page's built-in contents.
+    pub fn write_page(&self, queue: &wgpu::Queue, page: u32, image: Option<&image::RgbaImage>) {
+        let pixels = Self::page_pixels(page, image);
+        self.write_region(queue, page, [0, 0], &pixels);
+    }
+    /// Overwrites part of a page, whatever falls outside of the page is ignored
+    pub fn write_region(
+        &self,
+        queue: &wgpu::Queue,
+        page: u32,
+        [x, y]: [u32; 2],
+        image: &image::RgbaImage,
+    ) {
+        let wgpu::Extent3d { width, height, .. } = Self::PAGE_EXTENT;
+        let w = image.width().min(width.saturating_sub(x));
+        let h = image.height().min(height.saturating_sub(y));
+        if w == 0 || h == 0 || page >= Self::PAGES {
+            return;
+        }
         queue.write_texture(
             wgpu::ImageCopyTexture {
-                texture: &texture,
+                texture: &self.texture,
                 mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
+                origin: wgpu::Origin3d { x, y, z: page },
                 aspect: wgpu::TextureAspect::All,
             },
-            &rgba,
+            image,
             wgpu::ImageDataLayout {
                 offset: 0,
                 bytes_per_row: Some(
-                    std::num::NonZeroU32::new(4 * Self::EXTENT.width)
+                    std::num::NonZeroU32::new(4 * image.width())
                         .expect("Image dimensions are invalid non-zero uint32")
                         .into(),
                 ),
                 rows_per_image: Some(
-                    std::num::NonZeroU32::new(Self::EXTENT.height)
+                    std::num::NonZeroU32::new(image.height())
                         .expect("Image dimensions are invalid non-zero uint32")
                         .into(),
                 ),
             },
-            Self::EXTENT,
+            wgpu::Extent3d {
+                width: w,
+                height: h,
+                depth_or_array_layers: 1,
+            },
         );
-        Self {
-            texture,
-            layout,
-            bind_group,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn viewports_keep_the_canvas_centered() {
+        let canvas = [256., 144.];
+        assert_eq!(
+            ScaleMode::Integer.viewport([600., 400.], canvas),
+            [44., 56., 512., 288.]
+        );
+        assert_eq!(
+            ScaleMode::AspectFit.viewport([600., 400.], canvas),
+            [0., 31., 600., 337.5]
+        );
+        assert_eq!(
+            ScaleMode::Stretch.viewport([600., 400.], canvas),
+            [0., 0., 600., 400.]
+        );
+        // Too small to integer scale, so it fits instead
+        assert_eq!(
+            ScaleMode::Integer.viewport([128., 144.], canvas),
+            ScaleMode::AspectFit.viewport([128., 144.], canvas)
+        );
+    }
+
+    #[test]
+    fn inverse_transforms_undo_them() {
+        let transform = Transform {
+            translate: [10., -4.],
+            scale: 2.5,
+            rotation: 0.7,
+        };
+        for point in [[0., 0.], [3., 7.], [-20., 5.5]] {
+            let [x, y] = transform.inverse().apply(transform.apply(point));
+            assert!((x - point[0]).abs() < 1e-4 && (y - point[1]).abs() < 1e-4);
         }
     }
+
+    #[test]
+    fn window_positions_map_to_cart_coordinates() {
+        let canvas = [256., 144.];
+        let window = [600., 400.];
+        let to_canvas = |zoom, pos| ScaleMode::Integer.to_canvas(window, canvas, zoom, pos);
+        assert_eq!(to_canvas(1., [44., 56.]), [0., 0.]);
+        assert_eq!(to_canvas(1., [556., 344.]), [256., 144.]);
+        // Zooming in shows less of the cart's space
+        assert_eq!(to_canvas(2., [556., 344.]), [128., 72.]);
+        assert_eq!(to_canvas(0.5, [300., 200.]), [256., 144.]);
+    }
 }
@@ -1,4 +1,3 @@
-
 use winit::event::ElementState;
 
 #[repr(C, packed)]
@@ -52,14 +51,14 @@ impl InputState {
 
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct MouseButtons<T: Copy> {
+pub struct MouseButtons<T> {
     pub left: T,
     pub right: T,
 }
 
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct Buttons<T: Copy> {
+pub struct Buttons<T> {
     pub up: T,
     pub down: T,
     pub left: T,
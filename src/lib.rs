@@ -1,14 +1,30 @@
-mod gpu;
+pub mod capture;
+pub mod gpu;
 mod input;
-mod wasm;
+pub mod perf;
+pub mod post;
+pub mod raster;
+pub mod schedule;
+pub mod settings;
+pub mod text;
+pub mod tilemap;
+pub mod wasm;
 use winit::{
     event::*,
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Fullscreen, WindowBuilder},
 };
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen(start))]
 pub async fn run() {
+    run_with(|_| true).await
+}
+
+/// Runs the app like `run`, passing the stats of every presented frame to `on_frame`
+///
+/// This is meant for automated benchmarks, `on_frame` returns `false` to exit. The
+/// event loop never returns, so results have to be reported from `on_frame` itself.
+pub async fn run_with(mut on_frame: impl FnMut(&perf::FrameStats) -> bool + 'static) {
     let event_loop = EventLoop::new();
     #[cfg(not(target_arch = "wasm32"))]
     let window = {
@@ -33,12 +49,47 @@ pub async fn run() {
         window.set_outer_position(winit::dpi::PhysicalPosition::new(x, y));
         window
     };
+    let mut settings = settings::Settings::load();
+    if settings.fullscreen {
+        window.set_fullscreen(Some(Fullscreen::Borderless(None)));
+    }
     let mut gpu = gpu::GPUContext::new(window).await;
+    gpu.set_vsync(settings.vsync);
     let mut renderer = gpu::Renderer::new(&gpu);
+    renderer.scale_mode = settings.scale_mode;
+    renderer.max_quads = settings.max_quads;
+    renderer.post_effects = settings.post_effects();
     let mut app: Option<wasm::App> = None;
     #[cfg(not(target_arch = "wasm32"))]
+    let mut screenshot_scale = 1;
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut recording_options = capture::RecordingOptions::default();
+    #[cfg(not(target_arch = "wasm32"))]
     for arg in std::env::args() {
-        if arg.ends_with(".wasm") {
+        if let Some(scale) = arg.strip_prefix("--screenshot-scale=") {
+            match scale.parse::<u32>() {
+                Ok(scale) if scale > 0 => screenshot_scale = scale,
+                _ => eprintln!("Invalid screenshot scale: {:?}", scale),
+            }
+        } else if let Some(format) = arg.strip_prefix("--record-format=") {
+            match format {
+                "gif" => recording_options.format = capture::RecordingFormat::Gif,
+                "apng" => recording_options.format = capture::RecordingFormat::Apng,
+                _ => eprintln!("Invalid recording format: {:?}", format),
+            }
+        } else if let Some(scale) = arg.strip_prefix("--record-scale=") {
+            match scale.parse::<u32>() {
+                Ok(scale) if scale > 0 => recording_options.scale = scale,
+                _ => eprintln!("Invalid recording scale: {:?}", scale),
+            }
+        } else if let Some(secs) = arg.strip_prefix("--record-max-secs=") {
+            match secs.parse::<u64>() {
+                Ok(secs) if secs > 0 => {
+                    recording_options.max_duration = instant::Duration::from_secs(secs)
+                }
+                _ => eprintln!("Invalid recording length: {:?}", secs),
+            }
+        } else if arg.ends_with(".wasm") {
             match wasm::App::from_file(&arg) {
                 Err(err) => eprintln!("Error creating cart from file: {:?}", err),
                 Ok(next_app) => {
@@ -48,24 +99,64 @@ pub async fn run() {
         }
     }
 
-    let mut tick = 0;
+    let mut scheduler = schedule::Scheduler::default();
     let mut user_input = input::UserInput::new();
     event_loop.run(move |event, _, control_flow| match event {
         Event::MainEventsCleared => {
-            if renderer.globals.tick > tick {
+            if let Some(current_app) = &mut app {
+                let rate = current_app.update_rate();
+                scheduler.set_rate(rate.unwrap_or(schedule::Scheduler::DEFAULT_RATE));
+                renderer.frame_dur = instant::Duration::from_secs(1) / scheduler.rate();
+            }
+            let steps = scheduler.due_steps(instant::Instant::now());
+            for step in 1..=steps {
+                // Only what the last update draws onto the canvas is presented
                 if let Some(current_app) = &mut app {
                     current_app.clear_vertex_data();
                     current_app.update_input(user_input);
+                    let run_start = instant::Instant::now();
                     if let Err(err) = current_app.run() {
                         eprintln!("App error: {:?}", err);
                     }
-                    current_app.read_vertex_data(|data| {
-                        renderer.write_vertexes(&gpu, &bytemuck::cast_slice(data));
+                    renderer.perf.record_run(run_start.elapsed());
+                    renderer.set_zoom(current_app.zoom());
+                    if let Some(palette) = current_app.take_palette() {
+                        renderer.write_palette(&gpu, &palette);
+                    }
+                    for write in current_app.take_pages() {
+                        renderer.write_page(&gpu, &write);
+                    }
+                    if step < steps {
+                        // Earlier updates still draw onto offscreen targets, which keep
+                        // their pixels from one update to the next
+                        renderer.write_targets(&gpu, current_app.targets());
+                        current_app.read_vertex_data(|data, batches| {
+                            if let Err(err) = renderer.write_vertexes(&gpu, data, batches) {
+                                eprintln!("App error: {:?}", err);
+                            }
+                        });
+                        renderer.render_targets(&gpu, scheduler.tick() - (steps - step));
+                    }
+                }
+                user_input.main_events_cleared();
+            }
+            if steps > 0 {
+                if let Some(current_app) = &mut app {
+                    renderer.write_targets(&gpu, current_app.targets());
+                    let framebuffer = current_app
+                        .read_framebuffer(|pixels| renderer.write_framebuffer(&gpu, pixels));
+                    if let Err(err) = framebuffer {
+                        eprintln!("App error: {:?}", err);
+                    }
+                    current_app.read_vertex_data(|data, batches| {
+                        if let Err(err) = renderer.write_vertexes(&gpu, data, batches) {
+                            eprintln!("App error: {:?}", err);
+                        }
                     });
                     #[cfg(not(target_arch = "wasm32"))]
                     {
                         if let Some(path) = &current_app.module_filepath {
-                            match std::fs::File::open(&path) {
+                            match std::fs::File::open(path) {
                                 Err(err) => eprintln!("Error loading cart: {:?}", err),
                                 Ok(file) => match file.metadata() {
                                     Err(err) => eprintln!("Error reading cart metadata: {:?}", err),
@@ -94,18 +185,35 @@ pub async fn run() {
                         }
                     }
                 }
-                user_input.main_events_cleared();
-                tick = renderer.globals.tick;
+                renderer.set_tick(scheduler.tick());
             }
-            match renderer.render(&gpu) {
-                Ok(inst) => {
-                    *control_flow = ControlFlow::WaitUntil(inst);
-                }
+            // Without vsync there is nothing new to present until the next update
+            let presented = match settings.vsync || steps > 0 {
+                true => renderer.render(&gpu),
+                false => Ok(false),
+            };
+            let frame_presented = matches!(presented, Ok(true));
+            *control_flow = match presented {
+                Ok(true) if settings.vsync => ControlFlow::Poll,
+                Ok(_) => ControlFlow::WaitUntil(scheduler.next_update()),
                 Err(wgpu::SurfaceError::Lost) => {
                     gpu.surface.configure(&gpu.device, &gpu.config);
+                    ControlFlow::WaitUntil(scheduler.next_update())
+                }
+                Err(wgpu::SurfaceError::OutOfMemory) => ControlFlow::Exit,
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    ControlFlow::WaitUntil(scheduler.next_update())
+                }
+            };
+            if frame_presented && !on_frame(&renderer.perf.last_frame()) {
+                *control_flow = ControlFlow::Exit;
+            }
+            for recording in renderer.finished_recordings() {
+                match recording {
+                    Ok(path) => println!("Saved recording {:?}", path),
+                    Err(err) => eprintln!("Error saving recording: {:?}", err),
                 }
-                Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
-                Err(e) => eprintln!("{:?}", e),
             }
         }
         Event::WindowEvent {
@@ -134,119 +242,172 @@ pub async fn run() {
                 }
             }
             WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-            WindowEvent::KeyboardInput { input, .. } => match input {
-                KeyboardInput {
-                    state,
-                    virtual_keycode,
-                    ..
-                } => {
-                    use winit::event::{ElementState::*, VirtualKeyCode::*};
-                    match (state, virtual_keycode) {
-                        // Up
-                        (Pressed, Some(Up) | Some(W)) => {
-                            user_input.buttons.up = user_input.buttons.up.next(*state);
-                        }
-                        (Released, Some(Up) | Some(W)) => {
-                            user_input.buttons.up = user_input.buttons.up.next(*state);
-                        }
-                        // Down
-                        (Pressed, Some(Down) | Some(S)) => {
-                            user_input.buttons.down = user_input.buttons.down.next(*state);
-                        }
-                        (Released, Some(Down) | Some(S)) => {
-                            user_input.buttons.down = user_input.buttons.down.next(*state);
-                        }
-                        // Left
-                        (Pressed, Some(Left) | Some(A)) => {
-                            user_input.buttons.left = user_input.buttons.left.next(*state);
-                        }
-                        (Released, Some(Left) | Some(A)) => {
-                            user_input.buttons.left = user_input.buttons.left.next(*state);
-                        }
-                        // Right
-                        (Pressed, Some(Right) | Some(D)) => {
-                            user_input.buttons.right = user_input.buttons.right.next(*state);
-                        }
-                        (Released, Some(Right) | Some(D)) => {
-                            user_input.buttons.right = user_input.buttons.right.next(*state);
-                        }
-                        // A
-                        (Pressed, Some(Z)) => {
-                            user_input.buttons.a = user_input.buttons.a.next(*state);
-                        }
-                        (Released, Some(Z)) => {
-                            user_input.buttons.a = user_input.buttons.a.next(*state);
-                        }
-                        // B
-                        (Pressed, Some(X)) => {
-                            user_input.buttons.b = user_input.buttons.b.next(*state);
-                        }
-                        (Released, Some(X)) => {
-                            user_input.buttons.b = user_input.buttons.b.next(*state);
-                        }
-                        // X
-                        (Pressed, Some(C)) => {
-                            user_input.buttons.x = user_input.buttons.x.next(*state);
-                        }
-                        (Released, Some(C)) => {
-                            user_input.buttons.x = user_input.buttons.x.next(*state);
-                        }
-                        // Y
-                        (Pressed, Some(V)) => {
-                            user_input.buttons.y = user_input.buttons.y.next(*state);
-                        }
-                        (Released, Some(V)) => {
-                            user_input.buttons.y = user_input.buttons.y.next(*state);
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state,
+                        virtual_keycode,
+                        ..
+                    },
+                ..
+            } => {
+                use winit::event::{ElementState::*, VirtualKeyCode::*};
+                match (state, virtual_keycode) {
+                    // Up
+                    (Pressed, Some(Up) | Some(W)) => {
+                        user_input.buttons.up = user_input.buttons.up.next(*state);
+                    }
+                    (Released, Some(Up) | Some(W)) => {
+                        user_input.buttons.up = user_input.buttons.up.next(*state);
+                    }
+                    // Down
+                    (Pressed, Some(Down) | Some(S)) => {
+                        user_input.buttons.down = user_input.buttons.down.next(*state);
+                    }
+                    (Released, Some(Down) | Some(S)) => {
+                        user_input.buttons.down = user_input.buttons.down.next(*state);
+                    }
+                    // Left
+                    (Pressed, Some(Left) | Some(A)) => {
+                        user_input.buttons.left = user_input.buttons.left.next(*state);
+                    }
+                    (Released, Some(Left) | Some(A)) => {
+                        user_input.buttons.left = user_input.buttons.left.next(*state);
+                    }
+                    // Right
+                    (Pressed, Some(Right) | Some(D)) => {
+                        user_input.buttons.right = user_input.buttons.right.next(*state);
+                    }
+                    (Released, Some(Right) | Some(D)) => {
+                        user_input.buttons.right = user_input.buttons.right.next(*state);
+                    }
+                    // A
+                    (Pressed, Some(Z)) => {
+                        user_input.buttons.a = user_input.buttons.a.next(*state);
+                    }
+                    (Released, Some(Z)) => {
+                        user_input.buttons.a = user_input.buttons.a.next(*state);
+                    }
+                    // B
+                    (Pressed, Some(X)) => {
+                        user_input.buttons.b = user_input.buttons.b.next(*state);
+                    }
+                    (Released, Some(X)) => {
+                        user_input.buttons.b = user_input.buttons.b.next(*state);
+                    }
+                    // X
+                    (Pressed, Some(C)) => {
+                        user_input.buttons.x = user_input.buttons.x.next(*state);
+                    }
+                    (Released, Some(C)) => {
+                        user_input.buttons.x = user_input.buttons.x.next(*state);
+                    }
+                    // Y
+                    (Pressed, Some(V)) => {
+                        user_input.buttons.y = user_input.buttons.y.next(*state);
+                    }
+                    (Released, Some(V)) => {
+                        user_input.buttons.y = user_input.buttons.y.next(*state);
+                    }
+                    // START
+                    (Pressed, Some(Space)) => {
+                        user_input.buttons.start = user_input.buttons.start.next(*state);
+                    }
+                    (Released, Some(Space)) => {
+                        user_input.buttons.start = user_input.buttons.start.next(*state);
+                    }
+                    // SELECT
+                    (Pressed, Some(Return)) => {
+                        user_input.buttons.select = user_input.buttons.select.next(*state);
+                    }
+                    (Released, Some(Return)) => {
+                        user_input.buttons.select = user_input.buttons.select.next(*state);
+                    }
+                    // Performance HUD
+                    (Released, Some(F3)) => {
+                        renderer.perf.toggle();
+                    }
+                    // CRT effects
+                    (Released, Some(F6)) => {
+                        settings.crt = !settings.crt;
+                        renderer.post_effects = settings.post_effects();
+                        if let Err(err) = settings.save() {
+                            eprintln!("Error saving settings: {:?}", err);
                         }
-                        // START
-                        (Pressed, Some(Space)) => {
-                            user_input.buttons.start = user_input.buttons.start.next(*state);
+                    }
+                    // Scale mode
+                    (Released, Some(F8)) => {
+                        renderer.scale_mode = renderer.scale_mode.next();
+                        println!("Scale mode: {}", renderer.scale_mode.name());
+                        settings.scale_mode = renderer.scale_mode;
+                        if let Err(err) = settings.save() {
+                            eprintln!("Error saving settings: {:?}", err);
                         }
-                        (Released, Some(Space)) => {
-                            user_input.buttons.start = user_input.buttons.start.next(*state);
+                    }
+                    // Fullscreen
+                    (Released, Some(F11)) => {
+                        settings.fullscreen = gpu.window.fullscreen().is_none();
+                        if settings.fullscreen {
+                            gpu.window
+                                .set_fullscreen(Some(Fullscreen::Borderless(None)));
+                        } else {
+                            gpu.window.set_fullscreen(None);
                         }
-                        // SELECT
-                        (Pressed, Some(Return)) => {
-                            user_input.buttons.select = user_input.buttons.select.next(*state);
+                        if let Err(err) = settings.save() {
+                            eprintln!("Error saving settings: {:?}", err);
                         }
-                        (Released, Some(Return)) => {
-                            user_input.buttons.select = user_input.buttons.select.next(*state);
+                    }
+                    // Recording, encoding needs a thread and a file system
+                    #[cfg(not(target_arch = "wasm32"))]
+                    (Released, Some(F9)) => {
+                        if renderer.is_recording() {
+                            println!("Stopped recording");
+                            renderer.stop_recording(&gpu);
+                        } else {
+                            println!("Started recording");
+                            let dir = std::path::Path::new(".");
+                            renderer.start_recording(dir, recording_options);
                         }
-                        (Pressed, Some(Escape)) => {
-                            *control_flow = ControlFlow::Exit;
+                    }
+                    // Screenshot, the web build has no file system to save it to
+                    #[cfg(not(target_arch = "wasm32"))]
+                    (Released, Some(F12)) => {
+                        let dir = std::path::Path::new(".");
+                        match renderer.save_screenshot(&gpu, dir, screenshot_scale) {
+                            Ok(path) => println!("Saved screenshot {:?}", path),
+                            Err(err) => eprintln!("Error saving screenshot: {:?}", err),
                         }
-                        _ => (),
                     }
+                    (Pressed, Some(Escape)) => {
+                        *control_flow = ControlFlow::Exit;
+                    }
+                    _ => (),
                 }
-            },
+            }
             WindowEvent::MouseInput { button, state, .. } => {
                 use MouseButton::*;
                 match button {
                     Left => {
-                        user_input.mouse.left = user_input.mouse.left.next(state.clone());
+                        user_input.mouse.left = user_input.mouse.left.next(*state);
                         println!("MouseInput {:?}", button);
                     }
                     Right => {
-                        user_input.mouse.right = user_input.mouse.right.next(state.clone());
+                        user_input.mouse.right = user_input.mouse.right.next(*state);
                         println!("MouseInput {:?}", button);
                     }
                     _ => (),
                 }
             }
             WindowEvent::CursorMoved { position, .. } => {
-                let size = position.to_logical::<i32>(gpu.window.scale_factor());
-                let x = size.x / 4;
-                let y = size.y / 4;
-                user_input.cursor = [x, y];
+                user_input.cursor = renderer.window_to_canvas(&gpu, position.x, position.y);
             }
             WindowEvent::MouseWheel { delta, .. } => {
                 use MouseScrollDelta::*;
                 user_input.wheel = match delta {
                     PixelDelta(delta) => {
-                        let delta = delta.to_logical::<i32>(gpu.window.scale_factor());
-                        let x = delta.x / 4;
-                        let y = delta.y / 4;
-                        [x, y]
+                        let [x0, y0] = renderer.window_to_canvas(&gpu, 0., 0.);
+                        let [x1, y1] = renderer.window_to_canvas(&gpu, delta.x, delta.y);
+                        [x1 - x0, y1 - y0]
                     }
                     LineDelta(x, y) => {
                         // We'll just call it 8 pixels per line I guess 🤷🏽‍♂️
@@ -7,11 +7,22 @@ struct Globals {
 @group(0) @binding(0)
 var<uniform> globals: Globals;
 
+// 256 colors packed four to a vec4 to satisfy uniform array stride rules
+struct Palette {
+    colors: array<vec4<u32>, 64>,
+}
+
+@group(2) @binding(0)
+var<uniform> palette: Palette;
+
+const PALETTE_FILL: u32 = 1u;
+const PALETTE_TEX_FILL: u32 = 2u;
+const PALETTE_BORDER_COLOR: u32 = 4u;
+
 // Vertex shader
 //------------------------------------------------------------------------------
 
 struct VertexInput {
-    @location(0) index: u32,
     @location(1) rect: vec4<f32>,
     @location(2) fill: u32,
     @location(3) tex_rect: vec4<f32>,
@@ -22,13 +33,16 @@ struct VertexInput {
     @location(8) border_radius: vec2<u32>,
     @location(9) border_size: u32,
     @location(10) border_color: vec4<u32>,
+    @location(11) palette: u32,
+    @location(12) tint: u32,
+    @location(13) page: u32,
 }
 
 struct VertexOutput {
     @builtin(position) pos: vec4<f32>,
     @location(0) bg_fill: vec4<f32>,
-    @location(1) tex_coords: vec2<f32>,
-    @location(2) tex_area: f32,
+    @location(1) tex_coords: vec3<f32>, // z is the spritesheet page
+    @location(2) tint: vec4<f32>,
     @location(3) tex_fill: vec4<f32>,
     @location(4) rect: vec4<f32>,
     @location(5) angle: f32,
@@ -47,8 +61,8 @@ struct VertexOutput {
 
 @vertex
 fn vs_main(in: VertexInput, @builtin(vertex_index) n: u32) -> VertexOutput {
-    // let i = n % 6u;
-    let i = in.index;
+    // Each quad is an instance drawn with vertices 0..6
+    let i = n % 6u;
     let tick = f32(globals.tick);
     // let tick = 0.;
     let viewport_size = floor(globals.viewport / globals.scale);
@@ -107,8 +121,7 @@ fn vs_main(in: VertexInput, @builtin(vertex_index) n: u32) -> VertexOutput {
     // Apply rotation
     let angle = in.rotation_base + (in.rotation_rate * tick);
     var pos = in.rect.xy;
-    let size = in.rect.zw;
-    var rot_origin = pos + size / 2.0;
+    var rot_origin = pos + in.rotation_origin;
     if angle != 0. {
         let rot_mat = mat2x2<f32>(
             cos(angle),
@@ -125,10 +138,10 @@ fn vs_main(in: VertexInput, @builtin(vertex_index) n: u32) -> VertexOutput {
 
     var out: VertexOutput;
     out.pos = vec4<f32>(vec_pos * vec2<f32>(1., -1.), 0., 1.);
-    out.tex_coords = tex_pos;
-    out.bg_fill = to_rgba(in.fill);
-    out.tex_fill = to_rgba(in.tex_fill);
-    out.tex_area = in.tex_rect.z * in.tex_rect.w;
+    out.tex_coords = vec3<f32>(tex_pos, f32(in.page));
+    out.bg_fill = resolve_color(in.fill, in.palette & PALETTE_FILL);
+    out.tex_fill = resolve_color(in.tex_fill, in.palette & PALETTE_TEX_FILL);
+    out.tint = to_rgba(in.tint);
     out.border_top_left_radius = vec2<f32>(
         min(to_vec4_f32(in.border_radius.x).x, in.rect.z / 2.),
         min(to_vec4_f32(in.border_radius.y).x, in.rect.w / 2.),
@@ -149,10 +162,11 @@ fn vs_main(in: VertexInput, @builtin(vertex_index) n: u32) -> VertexOutput {
     out.angle = angle;
     out.rot_origin = rot_origin;
     out.border_size = to_vec4_f32(in.border_size);
-    out.border_color_t = to_rgba(in.border_color.x);
-    out.border_color_r = to_rgba(in.border_color.y);
-    out.border_color_b = to_rgba(in.border_color.z);
-    out.border_color_l = to_rgba(in.border_color.w);
+    let palette_border = in.palette & PALETTE_BORDER_COLOR;
+    out.border_color_t = resolve_color(in.border_color.x, palette_border);
+    out.border_color_r = resolve_color(in.border_color.y, palette_border);
+    out.border_color_b = resolve_color(in.border_color.z, palette_border);
+    out.border_color_l = resolve_color(in.border_color.w, palette_border);
     // out.border_color_t = vec4<f32>(0., 0., 1., 1.);
     // out.border_color_r = vec4<f32>(.5, 0., .5, 1.);
     // out.border_color_b = vec4<f32>(1., 0., .5, 1.);
@@ -160,6 +174,16 @@ fn vs_main(in: VertexInput, @builtin(vertex_index) n: u32) -> VertexOutput {
     return out;
 }
 
+// Looks up palette indices, raw colors are passed through
+fn resolve_color(color: u32, from_palette: u32) -> vec4<f32> {
+    if from_palette != 0u {
+        let i = color & 0xFFu;
+        return to_rgba(palette.colors[i / 4u][i % 4u]);
+    }
+    return to_rgba(color);
+}
+
+// Colors are packed as 0xAABBGGRR (red in the lowest byte)
 fn to_rgba(color: u32) -> vec4<f32> {
 
     let r = f32(((color >> 0u) & 0xFFu)) / 255.;
@@ -185,10 +209,10 @@ fn to_vec4_f32(color: u32) -> vec4<f32> {
 
 // Fragment shader
 //------------------------------------------------------------------------------
-// TODO: explore texture-arrays: https://github.com/gfx-rs/wgpu/blob/trunk/wgpu/examples/texture-arrays/main.rs
 
+// One layer per spritesheet page, the canvas is bound as a single page
 @group(1) @binding(0)
-var t_spritesheet: texture_2d<f32>;
+var t_spritesheet: texture_2d_array<f32>;
 
 @group(1) @binding(1)
 var s_spritesheet: sampler;
@@ -198,7 +222,8 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let tex_dims = textureDimensions(t_spritesheet);
     let tex_dimsf = vec2<f32>(tex_dims.xy);
     let uv = in.tex_coords.xy / tex_dimsf;
-    let tex_color = textureSample(t_spritesheet, s_spritesheet, uv);
+    let page = i32(round(in.tex_coords.z));
+    let tex_color = textureSample(t_spritesheet, s_spritesheet, uv, page);
     
     // Unapply transformation so we can do calculations easier
     let rot_mat = mat2x2<f32>(
@@ -305,7 +330,7 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
             if !intersects_ellipse(px, py, e.x, e.y, e.z, e.w) {
                     discard;
             }
-            if ellipse_edge_distance(px, py, e.x, e.y, e.z, e.w) <= bst { // bottom
+            if ellipse_edge_distance(px, py, e.x, e.y, e.z, e.w) <= bsb { // bottom
                 if intersects_rect(px, py, r.x, r.y + r.w * .5, r.z, r.w * .5) {
                     return in.border_color_b;
                 }
@@ -335,7 +360,7 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
             if !intersects_ellipse(px, py, e.x, e.y, e.z, e.w) {
                     discard;
             }
-            if ellipse_edge_distance(px, py, e.x, e.y, e.z, e.w) <= bst { // bottom
+            if ellipse_edge_distance(px, py, e.x, e.y, e.z, e.w) <= bsb { // bottom
                 if intersects_rect(px, py, r.x, r.y + r.w * .5, r.z, r.w * .5) {
                     return in.border_color_b;
                 }
@@ -379,7 +404,8 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         fill = in.bg_fill;
     }
     fill.a = ceil(fill.a); // Only 0 or 1 opacity supported
-    return fill;
+    // Tint alpha is the one way to get translucency
+    return fill * in.tint;
 
     // // If there's no sampled texture color, use the background fill
     // if tex_color.a == 0. {
@@ -0,0 +1,156 @@
+use crate::gpu::QuadVertex;
+use std::sync::{Arc, Mutex};
+
+use instant::{Duration, Instant};
+
+/// Number of frames kept for the rolling frame-time graph
+pub const HISTORY_LEN: usize = 64;
+
+// Colors are packed little-endian (0xAABBGGRR), see `to_rgba` in main.wgsl
+const HUD_BG: u32 = 0xff000000;
+const HUD_TEXT: u32 = 0xffffffff;
+const GRAPH_OK: u32 = 0xff00ff00;
+const GRAPH_SLOW: u32 = 0xff0000ff;
+
+/// Timings and counts collected for a single rendered frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// Time between this frame and the previous one
+    pub frame_time: Duration,
+    /// Time spent inside `App::run`
+    pub run_time: Duration,
+    /// Number of quads the cart pushed into `HostState.quads`
+    pub quad_count: usize,
+    /// Bytes uploaded by `Renderer::write_vertexes`
+    pub upload_bytes: usize,
+    /// CPU time spent encoding and submitting GPU work, not how long the GPU takes
+    pub encode_time: Duration,
+    /// From submitting the frame's GPU work to the GPU finishing it
+    ///
+    /// Only measured while the HUD is visible, since it makes the CPU wait for the GPU.
+    pub gpu_time: Duration,
+}
+
+#[derive(Debug)]
+pub struct PerfStats {
+    pub visible: bool,
+    current: FrameStats,
+    last: FrameStats,
+    history: [Duration; HISTORY_LEN],
+    history_len: usize,
+    history_pos: usize,
+    last_frame_at: Option<Instant>,
+    /// Set by the work done callback of the last timed frame
+    gpu_done: Arc<Mutex<Option<Duration>>>,
+}
+impl PerfStats {
+    pub fn new() -> Self {
+        Self {
+            visible: false,
+            current: FrameStats::default(),
+            last: FrameStats::default(),
+            history: [Duration::ZERO; HISTORY_LEN],
+            history_len: 0,
+            history_pos: 0,
+            last_frame_at: None,
+            gpu_done: Arc::default(),
+        }
+    }
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+    pub fn record_run(&mut self, dur: Duration) {
+        self.current.run_time = dur;
+    }
+    pub fn record_upload(&mut self, quad_count: usize, bytes: usize) {
+        self.current.quad_count = quad_count;
+        self.current.upload_bytes = bytes;
+    }
+    pub fn record_encode(&mut self, dur: Duration) {
+        self.current.encode_time = dur;
+    }
+    /// Times the GPU work submitted since `submitted`, the result is picked up by `end_frame`
+    /// once the GPU is done
+    pub fn time_gpu(&self, queue: &wgpu::Queue, submitted: Instant) {
+        let done = self.gpu_done.clone();
+        queue.on_submitted_work_done(move || {
+            *done.lock().unwrap() = Some(submitted.elapsed());
+        });
+    }
+    /// Closes out the current frame and makes its numbers available via `last_frame`
+    pub fn end_frame(&mut self, now: Instant) {
+        if let Some(gpu_time) = self.gpu_done.lock().unwrap().take() {
+            self.current.gpu_time = gpu_time;
+        }
+        if let Some(prev) = self.last_frame_at {
+            self.current.frame_time = now.duration_since(prev);
+            self.history[self.history_pos] = self.current.frame_time;
+            self.history_pos = (self.history_pos + 1) % HISTORY_LEN;
+            self.history_len = (self.history_len + 1).min(HISTORY_LEN);
+        }
+        self.last_frame_at = Some(now);
+        self.last = self.current;
+    }
+    /// Stats for the most recently completed frame
+    pub fn last_frame(&self) -> FrameStats {
+        self.last
+    }
+    /// Frame times from oldest to newest
+    pub fn history(&self) -> impl Iterator<Item = Duration> + '_ {
+        let start = (self.history_pos + HISTORY_LEN - self.history_len) % HISTORY_LEN;
+        (0..self.history_len).map(move |i| self.history[(start + i) % HISTORY_LEN])
+    }
+    /// Average frames per second over the rolling history
+    pub fn fps(&self) -> f32 {
+        let total: Duration = self.history().sum();
+        if total.is_zero() {
+            return 0.;
+        }
+        self.history_len as f32 / total.as_secs_f32()
+    }
+    /// Builds the HUD overlay as quads in canvas coordinates
+    pub fn hud_quads(&self) -> Vec<QuadVertex> {
+        let x = 2;
+        let y = 2;
+        let w = HISTORY_LEN as u32 + 4;
+        let graph_h = 20;
+        let stats = self.last;
+        let text = format!(
+            "FPS {:.1}\nRUN {:.2}MS\nQUADS {}\nUP {:.1}KB\nENC {:.2}MS\nGPU {:.2}MS",
+            self.fps(),
+            stats.run_time.as_secs_f32() * 1000.,
+            stats.quad_count,
+            stats.upload_bytes as f32 / 1024.,
+            stats.encode_time.as_secs_f32() * 1000.,
+            stats.gpu_time.as_secs_f32() * 1000.,
+        );
+        let lines = text.lines().count() as u32;
+        let text_h = lines * 6;
+        let mut quads = vec![];
+        let mut bg = QuadVertex::new([x as f32, y as f32, w as f32, (text_h + graph_h + 6) as f32]);
+        bg.fill = HUD_BG;
+        quads.push(bg);
+        for (i, line) in text.lines().enumerate() {
+            crate::text::push_text(&mut quads, x + 2, y + 2 + (i as i32 * 6), 0, HUD_TEXT, line);
+        }
+        // One pixel per millisecond, with slow frames (below 60fps) highlighted
+        let graph_bottom = (y as u32 + text_h + graph_h + 4) as f32;
+        for (i, dur) in self.history().enumerate() {
+            let ms = dur.as_secs_f32() * 1000.;
+            let h = ms.ceil().min(graph_h as f32);
+            let mut bar = QuadVertex::new([(x + 2) as f32 + i as f32, graph_bottom - h, 1., h]);
+            bar.fill = if ms > 1000. / 60. + 1. {
+                GRAPH_SLOW
+            } else {
+                GRAPH_OK
+            };
+            quads.push(bar);
+        }
+        quads
+    }
+}
+impl Default for PerfStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,152 @@
+use crate::gpu::{GPUContext, UniformBuffer};
+
+/// Effects applied while drawing the canvas onto the surface
+///
+/// Every effect is off when `None`, if they're all off the post-processing
+/// pipeline is skipped entirely and the canvas is blitted as-is.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PostEffects {
+    /// How dark the gaps between canvas rows get (0-1)
+    pub scanlines: Option<f32>,
+    /// Strength of the RGB aperture grille (0-1)
+    pub shadow_mask: Option<f32>,
+    /// Amount of barrel distortion (~0.1 is subtle)
+    pub curvature: Option<f32>,
+    /// Glow around bright pixels
+    pub bloom: Option<Bloom>,
+    /// How dark the corners get (0-1)
+    pub vignette: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bloom {
+    pub intensity: f32,
+    /// Blur radius in canvas pixels
+    pub radius: f32,
+}
+
+impl PostEffects {
+    pub const SCANLINES: u32 = 1;
+    pub const SHADOW_MASK: u32 = 2;
+    pub const CURVATURE: u32 = 4;
+    pub const BLOOM: u32 = 8;
+    pub const VIGNETTE: u32 = 16;
+
+    /// A reasonable CRT look with every effect turned on
+    pub fn crt() -> Self {
+        Self {
+            scanlines: Some(0.35),
+            shadow_mask: Some(0.2),
+            curvature: Some(0.06),
+            bloom: Some(Bloom {
+                intensity: 0.4,
+                radius: 2.,
+            }),
+            vignette: Some(0.5),
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+    pub fn uniforms(&self, source_size: [f32; 2], output_size: [f32; 2]) -> PostUniforms {
+        let mut effects = 0;
+        let mut flag = |enabled: bool, flag: u32| {
+            if enabled {
+                effects |= flag;
+            }
+        };
+        flag(self.scanlines.is_some(), Self::SCANLINES);
+        flag(self.shadow_mask.is_some(), Self::SHADOW_MASK);
+        flag(self.curvature.is_some(), Self::CURVATURE);
+        flag(self.bloom.is_some(), Self::BLOOM);
+        flag(self.vignette.is_some(), Self::VIGNETTE);
+        let bloom = self.bloom.unwrap_or(Bloom {
+            intensity: 0.,
+            radius: 0.,
+        });
+        PostUniforms {
+            source_size,
+            output_size,
+            effects,
+            scanlines: self.scanlines.unwrap_or(0.),
+            shadow_mask: self.shadow_mask.unwrap_or(0.),
+            curvature: self.curvature.unwrap_or(0.),
+            bloom_intensity: bloom.intensity,
+            bloom_radius: bloom.radius,
+            vignette: self.vignette.unwrap_or(0.),
+            _padding: 0.,
+        }
+    }
+}
+
+/// Uniform block of `post.wgsl`
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PostUniforms {
+    pub source_size: [f32; 2],
+    pub output_size: [f32; 2],
+    pub effects: u32,
+    pub scanlines: f32,
+    pub shadow_mask: f32,
+    pub curvature: f32,
+    pub bloom_intensity: f32,
+    pub bloom_radius: f32,
+    pub vignette: f32,
+    pub _padding: f32,
+}
+
+#[derive(Debug)]
+pub struct PostProcess {
+    pub uniforms: UniformBuffer<PostUniforms>,
+    pub pipeline: wgpu::RenderPipeline,
+}
+impl PostProcess {
+    pub fn new(gpu: &GPUContext, canvas_layout: &wgpu::BindGroupLayout) -> Self {
+        let shader_module = gpu
+            .device
+            .create_shader_module(wgpu::include_wgsl!("post.wgsl"));
+        let uniforms = UniformBuffer::new(
+            &gpu.device,
+            PostEffects::default().uniforms([0.; 2], [0.; 2]),
+        );
+        let pipeline =
+            gpu.device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Grainboy Post Pipeline"),
+                    layout: Some(&gpu.device.create_pipeline_layout(
+                        &wgpu::PipelineLayoutDescriptor {
+                            label: Some("Grainboy Post Pipeline Layout"),
+                            bind_group_layouts: &[&uniforms.layout, canvas_layout],
+                            push_constant_ranges: &[],
+                        },
+                    )),
+                    vertex: wgpu::VertexState {
+                        module: &shader_module,
+                        entry_point: "vs_main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader_module,
+                        entry_point: "fs_main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: gpu.config.format,
+                            blend: None,
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        cull_mode: None,
+                        ..Default::default()
+                    },
+                    multisample: wgpu::MultisampleState::default(),
+                    depth_stencil: None,
+                    multiview: None,
+                });
+        Self { uniforms, pipeline }
+    }
+    pub fn write(&self, queue: &wgpu::Queue, uniforms: PostUniforms) {
+        self.uniforms
+            .write(queue, 0, bytemuck::cast_slice(&[uniforms]));
+    }
+}
@@ -0,0 +1,120 @@
+// Post-processing pass: draws the canvas onto the surface with CRT-style effects
+
+const SCANLINES: u32 = 1u;
+const SHADOW_MASK: u32 = 2u;
+const CURVATURE: u32 = 4u;
+const BLOOM: u32 = 8u;
+const VIGNETTE: u32 = 16u;
+
+struct PostUniforms {
+    source_size: vec2<f32>,
+    output_size: vec2<f32>,
+    effects: u32,
+    scanlines: f32,
+    shadow_mask: f32,
+    curvature: f32,
+    bloom_intensity: f32,
+    bloom_radius: f32,
+    vignette: f32,
+    _padding: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> post: PostUniforms;
+
+@group(1) @binding(0)
+var t_canvas: texture_2d_array<f32>;
+
+@group(1) @binding(1)
+var s_canvas: sampler;
+
+// Vertex shader
+//------------------------------------------------------------------------------
+
+struct VertexOutput {
+    @builtin(position) pos: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// A single triangle that covers the whole viewport
+@vertex
+fn vs_main(@builtin(vertex_index) i: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((i << 1u) & 2u), f32(i & 2u));
+    var out: VertexOutput;
+    out.pos = vec4<f32>(uv * vec2<f32>(2., -2.) + vec2<f32>(-1., 1.), 0., 1.);
+    out.uv = uv;
+    return out;
+}
+
+// Fragment shader
+//------------------------------------------------------------------------------
+
+fn has_effect(effect: u32) -> bool {
+    return (post.effects & effect) != 0u;
+}
+
+// Pushes uvs outwards the further they are from the center
+fn barrel(uv: vec2<f32>, amount: f32) -> vec2<f32> {
+    var cc = uv * 2. - 1.;
+    cc *= 1. + amount * (cc.yx * cc.yx);
+    return cc * .5 + .5;
+}
+
+fn sample(uv: vec2<f32>) -> vec3<f32> {
+    // textureSampleLevel doesn't require uniform control flow
+    return textureSampleLevel(t_canvas, s_canvas, uv, 0, 0.).rgb;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let black = vec4<f32>(0., 0., 0., 1.);
+    var uv = in.uv;
+
+    // Barrel curvature
+    if has_effect(CURVATURE) {
+        uv = barrel(uv, post.curvature);
+        if uv.x < 0. || uv.x > 1. || uv.y < 0. || uv.y > 1. {
+            return black;
+        }
+    }
+
+    var color = sample(uv);
+
+    // Bloom: add a blurred copy of the bright parts back on top
+    if has_effect(BLOOM) {
+        let texel = post.bloom_radius / post.source_size;
+        var glow = vec3<f32>(0.);
+        for (var x = -2; x <= 2; x++) {
+            for (var y = -2; y <= 2; y++) {
+                let offset = vec2<f32>(f32(x), f32(y)) * texel * .5;
+                glow += sample(uv + offset);
+            }
+        }
+        glow /= 25.;
+        color += max(glow - .5, vec3<f32>(0.)) * 2. * post.bloom_intensity;
+    }
+
+    // Scanlines: darken the gaps between canvas rows
+    if has_effect(SCANLINES) {
+        let row = fract(uv.y * post.source_size.y);
+        let line = sin(row * 3.14159265);
+        color *= mix(1., line, post.scanlines);
+    }
+
+    // Shadow mask: aperture grille of red, green and blue columns in output pixels
+    if has_effect(SHADOW_MASK) {
+        let column = u32(in.pos.x) % 3u;
+        var mask = vec3<f32>(1. - post.shadow_mask);
+        mask[column] = 1.;
+        color *= mask;
+    }
+
+    // Vignette: fade out towards the corners
+    if has_effect(VIGNETTE) {
+        let edge = uv * (1. - uv);
+        let vig = clamp(edge.x * edge.y * 16., 0., 1.);
+        color *= mix(1., pow(vig, .25), post.vignette);
+    }
+
+    return vec4<f32>(color, 1.);
+}
@@ -0,0 +1,204 @@
+//! Pixel-exact rasterization of lines and polygons into axis-aligned spans
+//!
+//! Every span is an xywh rect that can be drawn as a plain `QuadVertex`, which
+//! keeps these shapes in the same pipeline, layer and clip batches as quads.
+
+/// Bresenham line from (x0, y0) to (x1, y1), both ends inclusive, clipped to `bounds`
+///
+/// Consecutive pixels are merged into horizontal runs for mostly-horizontal
+/// lines and vertical runs for mostly-vertical ones. Only the steps inside
+/// `bounds` (xywh) are visited, so far away endpoints cost nothing extra.
+pub fn line(x0: i32, y0: i32, x1: i32, y1: i32, bounds: [i32; 4]) -> Vec<[i32; 4]> {
+    let [bx, by, bw, bh] = bounds.map(i64::from);
+    let (x0, y0, x1, y1) = (x0 as i64, y0 as i64, x1 as i64, y1 as i64);
+    let x_major = (x1 - x0).abs() >= (y1 - y0).abs();
+    // Step along the major axis `a`, the minor axis `b` follows
+    let (a0, a1, b0, b1, a_range, b_range) = match x_major {
+        true => (x0, x1, y0, y1, bx..bx + bw, by..by + bh),
+        false => (y0, y1, x0, x1, by..by + bh, bx..bx + bw),
+    };
+    let (da, db) = ((a1 - a0).abs(), (b1 - b0).abs());
+    let (sa, sb) = ((a1 - a0).signum(), (b1 - b0).signum());
+    let (first, last) = match sa {
+        -1 => (a0 - (a_range.end - 1), a0 - a_range.start),
+        _ => (a_range.start - a0, a_range.end - 1 - a0),
+    };
+    let mut spans = vec![];
+    let mut push = |b: i64, a_min: i64, a_max: i64| {
+        let (b, a, len) = (b as i32, a_min as i32, (a_max - a_min + 1) as i32);
+        spans.push(match x_major {
+            true => [a, b, len, 1],
+            false => [b, a, 1, len],
+        });
+    };
+    let mut run: Option<(i64, i64, i64)> = None;
+    for k in first.max(0)..=last.min(da) {
+        // Same pixels as stepping Bresenham's error term k times
+        let m = match da {
+            0 => 0,
+            da => ((2 * k as i128 * db as i128 + da as i128) / (2 * da as i128)) as i64,
+        };
+        let (a, b) = (a0 + sa * k, b0 + sb * m);
+        match run {
+            Some((rb, lo, hi)) if rb == b => run = Some((b, lo.min(a), hi.max(a))),
+            _ => {
+                if let Some((rb, lo, hi)) = run.take() {
+                    push(rb, lo, hi);
+                }
+                if b_range.contains(&b) {
+                    run = Some((b, a, a));
+                }
+            }
+        }
+    }
+    if let Some((rb, lo, hi)) = run {
+        push(rb, lo, hi);
+    }
+    spans
+}
+
+/// Scanline fill of a polygon using the even-odd rule
+///
+/// A pixel is filled when its center is inside the polygon, so shapes that
+/// share an edge never overlap or leave gaps. Only rows and columns inside
+/// `bounds` (xywh) are filled.
+pub fn polygon(points: &[[f32; 2]], bounds: [i32; 4]) -> Vec<[i32; 4]> {
+    if points.len() < 3 {
+        return vec![];
+    }
+    let min_y = points.iter().map(|p| p[1]).fold(f32::INFINITY, f32::min);
+    let max_y = points
+        .iter()
+        .map(|p| p[1])
+        .fold(f32::NEG_INFINITY, f32::max);
+    let [bx, by, bw, bh] = bounds;
+    let rows = ((min_y - 0.5).ceil() as i32).max(by)
+        ..((max_y - 0.5).ceil() as i32).min(by.saturating_add(bh));
+    let mut spans = vec![];
+    let mut xs = vec![];
+    for y in rows {
+        let yc = y as f32 + 0.5;
+        xs.clear();
+        for (i, a) in points.iter().enumerate() {
+            let b = points[(i + 1) % points.len()];
+            if (a[1] <= yc) != (b[1] <= yc) {
+                xs.push(a[0] + (yc - a[1]) * (b[0] - a[0]) / (b[1] - a[1]));
+            }
+        }
+        xs.sort_by(f32::total_cmp);
+        for pair in xs.chunks_exact(2) {
+            let x0 = ((pair[0] - 0.5).ceil() as i32).max(bx);
+            let x1 = ((pair[1] - 0.5).ceil() as i32).min(bx.saturating_add(bw));
+            if x1 > x0 {
+                spans.push([x0, y, x1 - x0, 1]);
+            }
+        }
+    }
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVERYWHERE: [i32; 4] = [-100, -100, 200, 200];
+
+    /// Pixels covered by a set of spans, in row-major order
+    fn pixels(spans: &[[i32; 4]]) -> Vec<[i32; 2]> {
+        let mut pixels: Vec<[i32; 2]> = spans
+            .iter()
+            .flat_map(|&[x, y, w, h]| (y..y + h).flat_map(move |y| (x..x + w).map(move |x| [x, y])))
+            .collect();
+        pixels.sort_by_key(|&[x, y]| (y, x));
+        pixels
+    }
+
+    /// Plain error-stepping Bresenham, one pixel at a time
+    fn walk(x0: i32, y0: i32, x1: i32, y1: i32) -> Vec<[i32; 2]> {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        let mut pixels = vec![];
+        loop {
+            pixels.push([x, y]);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+        pixels.sort_by_key(|&[x, y]| (y, x));
+        pixels
+    }
+
+    #[test]
+    fn line_matches_bresenham() {
+        for x0 in -6..=6 {
+            for y0 in -6..=6 {
+                for x1 in -6..=6 {
+                    for y1 in -6..=6 {
+                        let spans = line(x0, y0, x1, y1, EVERYWHERE);
+                        assert_eq!(pixels(&spans), walk(x0, y0, x1, y1), "{x0},{y0} {x1},{y1}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn line_merges_runs() {
+        assert_eq!(line(0, 0, 9, 0, EVERYWHERE), vec![[0, 0, 10, 1]]);
+        assert_eq!(line(3, 7, 3, 2, EVERYWHERE), vec![[3, 2, 1, 6]]);
+        assert_eq!(
+            line(0, 0, 3, 1, EVERYWHERE),
+            vec![[0, 0, 2, 1], [2, 1, 2, 1]]
+        );
+    }
+
+    #[test]
+    fn line_is_clipped_to_bounds() {
+        let bounds = [0, 0, 256, 144];
+        assert_eq!(
+            line(-2_000_000_000, 5, 2_000_000_000, 5, bounds),
+            vec![[0, 5, 256, 1]]
+        );
+        assert_eq!(
+            line(i32::MIN, i32::MIN, i32::MAX, i32::MAX, bounds).len(),
+            144
+        );
+        assert!(line(10, 200, 10, 300, bounds).is_empty());
+        let clipped = pixels(&line(-20, -10, 40, 20, [0, 0, 10, 10]));
+        let full: Vec<[i32; 2]> = walk(-20, -10, 40, 20)
+            .into_iter()
+            .filter(|&[x, y]| (0..10).contains(&x) && (0..10).contains(&y))
+            .collect();
+        assert_eq!(clipped, full);
+    }
+
+    #[test]
+    fn polygon_fills_pixel_centers() {
+        let square = [[1., 1.], [4., 1.], [4., 3.], [1., 3.]];
+        assert_eq!(
+            polygon(&square, EVERYWHERE),
+            vec![[1, 1, 3, 1], [1, 2, 3, 1]]
+        );
+        assert!(polygon(&square[..2], EVERYWHERE).is_empty());
+    }
+
+    #[test]
+    fn polygon_is_clipped_to_bounds() {
+        let huge = [[-1e9, -1e9], [1e9, -1e9], [0., 1e9]];
+        let spans = polygon(&huge, [0, 0, 16, 8]);
+        assert_eq!(spans, (0..8).map(|y| [0, y, 16, 1]).collect::<Vec<_>>());
+    }
+}
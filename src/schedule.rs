@@ -0,0 +1,122 @@
+use instant::{Duration, Instant};
+
+/// Fixed-timestep game loop timing, independent of how often frames are presented
+///
+/// Carts update `rate` times per second. When the loop falls behind, the missed
+/// updates are caught up, at most `max_steps` at a time. Anything past that is
+/// dropped, so a long stall slows the game down instead of fast-forwarding it.
+#[derive(Clone, Copy, Debug)]
+pub struct Scheduler {
+    /// Most updates run back to back to catch up
+    pub max_steps: u32,
+    rate: u32,
+    step: Duration,
+    next_update: Instant,
+    tick: u32,
+}
+impl Scheduler {
+    /// Updates per second, unless the cart asks for another rate
+    pub const DEFAULT_RATE: u32 = 60;
+    pub const MAX_RATE: u32 = 1000;
+    pub const DEFAULT_MAX_STEPS: u32 = 5;
+    pub fn new(rate: u32) -> Self {
+        let rate = rate.clamp(1, Self::MAX_RATE);
+        let step = Duration::from_secs(1) / rate;
+        Self {
+            max_steps: Self::DEFAULT_MAX_STEPS,
+            rate,
+            step,
+            next_update: Instant::now() + step,
+            tick: 0,
+        }
+    }
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+    /// Changes the update rate, starting from the next scheduled update
+    pub fn set_rate(&mut self, rate: u32) {
+        let rate = rate.clamp(1, Self::MAX_RATE);
+        if rate != self.rate {
+            self.rate = rate;
+            self.step = Duration::from_secs(1) / rate;
+        }
+    }
+    /// Number of updates run so far
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+    /// When the next update is due
+    pub fn next_update(&self) -> Instant {
+        self.next_update
+    }
+    /// Number of updates due at `now`, the schedule moves past them
+    pub fn due_steps(&mut self, now: Instant) -> u32 {
+        let mut steps = 0;
+        while self.next_update <= now && steps < self.max_steps {
+            self.next_update += self.step;
+            self.tick += 1;
+            steps += 1;
+        }
+        // Too far behind, drop the backlog rather than running it next time
+        if self.next_update <= now {
+            self.next_update = now + self.step;
+        }
+        steps
+    }
+}
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_RATE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_nothing_before_the_first_update() {
+        let mut scheduler = Scheduler::new(60);
+        let first = scheduler.next_update();
+        assert_eq!(scheduler.due_steps(first - Duration::from_millis(1)), 0);
+        assert_eq!(scheduler.due_steps(first), 1);
+        assert_eq!(scheduler.due_steps(first), 0);
+        assert_eq!(scheduler.tick(), 1);
+    }
+
+    #[test]
+    fn catches_up_on_missed_updates() {
+        let mut scheduler = Scheduler::new(60);
+        let first = scheduler.next_update();
+        let step = Duration::from_secs(1) / 60;
+        assert_eq!(scheduler.due_steps(first + step * 2), 3);
+        assert_eq!(scheduler.tick(), 3);
+        // The schedule stays on the original grid
+        assert_eq!(scheduler.next_update(), first + step * 3);
+    }
+
+    #[test]
+    fn caps_catch_up_at_max_steps_and_drops_the_rest() {
+        let mut scheduler = Scheduler::new(60);
+        scheduler.max_steps = 4;
+        let first = scheduler.next_update();
+        let step = Duration::from_secs(1) / 60;
+        let now = first + step * 100;
+        assert_eq!(scheduler.due_steps(now), 4);
+        assert_eq!(scheduler.tick(), 4);
+        assert_eq!(scheduler.next_update(), now + step);
+        assert_eq!(scheduler.due_steps(now + step), 1);
+    }
+
+    #[test]
+    fn clamps_the_rate() {
+        let mut scheduler = Scheduler::new(0);
+        assert_eq!(scheduler.rate(), 1);
+        scheduler.set_rate(5000);
+        assert_eq!(scheduler.rate(), Scheduler::MAX_RATE);
+        let first = scheduler.next_update();
+        // The update already scheduled keeps its time, later ones use the new rate
+        assert_eq!(scheduler.due_steps(first), 1);
+        assert_eq!(scheduler.next_update(), first + Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::Result;
+
+use crate::gpu::ScaleMode;
+use crate::post::{Bloom, PostEffects};
+
+/// User preferences that persist between sessions
+///
+/// Stored as plain `key = value` lines so the file is easy to edit by hand.
+/// Unknown keys and malformed values are ignored and fall back to defaults.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub scale_mode: ScaleMode,
+    pub fullscreen: bool,
+    pub crt: bool,
+    /// Effects `crt` turns on, stored as `crt_*` keys that are a strength or `off`
+    pub crt_effects: PostEffects,
+    /// Most quads a cart can draw per frame
+    pub max_quads: usize,
+    /// Present frames in step with the display, cart updates run at their own rate
+    pub vsync: bool,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            scale_mode: ScaleMode::AspectFit,
+            fullscreen: false,
+            crt: false,
+            crt_effects: PostEffects::crt(),
+            max_quads: crate::gpu::VertexBuffer::DEFAULT_MAX_QUADS,
+            vsync: true,
+        }
+    }
+}
+impl Settings {
+    /// Post-processing to use, nothing unless `crt` is on
+    pub fn post_effects(&self) -> PostEffects {
+        match self.crt {
+            true => self.crt_effects,
+            false => PostEffects::default(),
+        }
+    }
+    /// Location of the settings file inside the platform config directory
+    pub fn path() -> Option<std::path::PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(std::path::PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(std::path::PathBuf::from))
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config"))
+            })?;
+        Some(config_dir.join("grainboy").join("settings.txt"))
+    }
+    pub fn load() -> Self {
+        match Self::path().map(std::fs::read_to_string) {
+            Some(Ok(text)) => Self::parse(&text),
+            _ => Self::default(),
+        }
+    }
+    pub fn save(&self) -> Result<()> {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => anyhow::bail!("no config directory available"),
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.serialize())?;
+        Ok(())
+    }
+    pub fn parse(text: &str) -> Self {
+        let mut settings = Self::default();
+        let mut bloom = settings.crt_effects.bloom.map(|bloom| bloom.intensity);
+        let mut bloom_radius = settings.crt_effects.bloom.map_or(2., |bloom| bloom.radius);
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match (key.trim(), value.trim()) {
+                ("scale_mode", value) => {
+                    if let Some(mode) = ScaleMode::from_name(value) {
+                        settings.scale_mode = mode;
+                    }
+                }
+                ("fullscreen", value) => {
+                    if let Ok(fullscreen) = value.parse() {
+                        settings.fullscreen = fullscreen;
+                    }
+                }
+                ("crt", value) => {
+                    if let Ok(crt) = value.parse() {
+                        settings.crt = crt;
+                    }
+                }
+                ("crt_scanlines", value) => {
+                    if let Some(effect) = parse_effect(value) {
+                        settings.crt_effects.scanlines = effect;
+                    }
+                }
+                ("crt_shadow_mask", value) => {
+                    if let Some(effect) = parse_effect(value) {
+                        settings.crt_effects.shadow_mask = effect;
+                    }
+                }
+                ("crt_curvature", value) => {
+                    if let Some(effect) = parse_effect(value) {
+                        settings.crt_effects.curvature = effect;
+                    }
+                }
+                ("crt_bloom", value) => {
+                    if let Some(effect) = parse_effect(value) {
+                        bloom = effect;
+                    }
+                }
+                ("crt_bloom_radius", value) => {
+                    if let Some(Some(radius)) = parse_effect(value) {
+                        bloom_radius = radius;
+                    }
+                }
+                ("crt_vignette", value) => {
+                    if let Some(effect) = parse_effect(value) {
+                        settings.crt_effects.vignette = effect;
+                    }
+                }
+                ("max_quads", value) => {
+                    if let Ok(max_quads) = value.parse() {
+                        settings.max_quads = max_quads;
+                    }
+                }
+                ("vsync", value) => {
+                    if let Ok(vsync) = value.parse() {
+                        settings.vsync = vsync;
+                    }
+                }
+                _ => (),
+            }
+        }
+        settings.crt_effects.bloom = bloom.map(|intensity| Bloom {
+            intensity,
+            radius: bloom_radius,
+        });
+        settings
+    }
+    pub fn serialize(&self) -> String {
+        let effects = &self.crt_effects;
+        let bloom = effects.bloom.map(|bloom| bloom.intensity);
+        let bloom_radius = effects.bloom.map_or(2., |bloom| bloom.radius);
+        format!(
+            "scale_mode = {}\nfullscreen = {}\ncrt = {}\n\
+             crt_scanlines = {}\ncrt_shadow_mask = {}\ncrt_curvature = {}\n\
+             crt_bloom = {}\ncrt_bloom_radius = {}\ncrt_vignette = {}\n\
+             max_quads = {}\nvsync = {}\n",
+            self.scale_mode.name(),
+            self.fullscreen,
+            self.crt,
+            effect_name(effects.scanlines),
+            effect_name(effects.shadow_mask),
+            effect_name(effects.curvature),
+            effect_name(bloom),
+            bloom_radius,
+            effect_name(effects.vignette),
+            self.max_quads,
+            self.vsync
+        )
+    }
+}
+
+/// A post-processing strength, `Some(None)` turns the effect off
+fn parse_effect(value: &str) -> Option<Option<f32>> {
+    match value {
+        "off" => Some(None),
+        value => match value.parse::<f32>() {
+            Ok(strength) if strength.is_finite() && strength >= 0. => Some(Some(strength)),
+            _ => None,
+        },
+    }
+}
+
+fn effect_name(effect: Option<f32>) -> String {
+    match effect {
+        Some(strength) => strength.to_string(),
+        None => "off".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_serialize() {
+        let settings = Settings {
+            scale_mode: ScaleMode::Integer,
+            fullscreen: true,
+            crt: true,
+            crt_effects: PostEffects {
+                scanlines: Some(0.5),
+                shadow_mask: None,
+                curvature: Some(0.125),
+                bloom: Some(Bloom {
+                    intensity: 0.25,
+                    radius: 3.,
+                }),
+                vignette: None,
+            },
+            max_quads: 1234,
+            vsync: false,
+        };
+        assert_eq!(Settings::parse(&settings.serialize()), settings);
+        let defaults = Settings::default();
+        assert_eq!(Settings::parse(&defaults.serialize()), defaults);
+    }
+
+    #[test]
+    fn ignores_unknown_keys_and_bad_values() {
+        let text = "crt = yes\nfullscreen=true\n  max_quads =  42 \nvolume = 11\nnot a setting\n";
+        let settings = Settings::parse(text);
+        assert!(!settings.crt);
+        assert!(settings.fullscreen);
+        assert_eq!(settings.max_quads, 42);
+        assert_eq!(settings.scale_mode, Settings::default().scale_mode);
+    }
+
+    #[test]
+    fn reads_crt_effect_strengths() {
+        let text = "crt_bloom_radius = 4\ncrt_bloom = 0.1\ncrt_curvature = off\n\
+                    crt_vignette = -1\ncrt_scanlines = nan\n";
+        let effects = Settings::parse(text).crt_effects;
+        let crt = PostEffects::crt();
+        assert_eq!(
+            effects.bloom,
+            Some(Bloom {
+                intensity: 0.1,
+                radius: 4.
+            })
+        );
+        assert_eq!(effects.curvature, None);
+        assert_eq!(effects.vignette, crt.vignette);
+        assert_eq!(effects.scanlines, crt.scanlines);
+        let settings = Settings {
+            crt: false,
+            ..Settings::parse(text)
+        };
+        assert!(!settings.post_effects().is_enabled());
+    }
+
+    #[test]
+    fn empty_text_is_the_defaults() {
+        assert_eq!(Settings::parse(""), Settings::default());
+    }
+}
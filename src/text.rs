@@ -0,0 +1,986 @@
+//! Bitmap fonts and text layout
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::gpu::{PageWrite, QuadVertex, Spritesheet};
+
+/// Where a glyph's pixels come from
+#[derive(Clone, Debug, PartialEq)]
+pub enum GlyphSource {
+    /// Already on a spritesheet page, like the built-in fonts
+    Page { page: u32, pos: [u32; 2] },
+    /// Copied into the glyph atlas the first time it's drawn
+    Bitmap(image::RgbaImage),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glyph {
+    pub size: [u32; 2],
+    /// From the pen position to the glyph's top-left corner, `offset[0]` is
+    /// the left bearing
+    pub offset: [i32; 2],
+    /// How far the pen moves after drawing the glyph
+    pub advance: u32,
+    pub source: GlyphSource,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Font {
+    pub line_height: u32,
+    pub glyphs: HashMap<char, Glyph>,
+    /// How far the pen moves for characters the font doesn't have
+    pub missing_advance: u32,
+    /// Added to the advance of the first char of a pair when the second follows it
+    pub kerning: HashMap<(char, char), i32>,
+}
+impl Font {
+    /// Ids 0, 1 and 2 are the small, medium and large fonts in `spritesheet.png`,
+    /// 3, 4 and 5 are proportional variants of them
+    pub fn builtin(id: u32) -> Option<&'static Font> {
+        static FONTS: std::sync::OnceLock<[Font; 6]> = std::sync::OnceLock::new();
+        FONTS
+            .get_or_init(|| {
+                let sheet = Spritesheet::builtin();
+                let [s, m, l] = [0, 1, 2].map(Self::from_glyph_table);
+                let proportional = [&s, &m, &l].map(|font| font.proportional(sheet));
+                let [ps, pm, pl] = proportional;
+                [s, m, l, ps, pm, pl]
+            })
+            .get(id as usize)
+    }
+    fn from_glyph_table(font: u8) -> Self {
+        let chars = (' '..='~').chain(['’', '🏠']);
+        let mut glyphs = HashMap::new();
+        let mut size = [0; 2];
+        for c in chars {
+            let (sx, sy, sw, sh) = get_glyph_coords(font, c);
+            size = [sw, sh];
+            let glyph = Glyph {
+                size,
+                offset: [0, 0],
+                advance: sw,
+                source: GlyphSource::Page {
+                    page: Spritesheet::FONT_PAGE,
+                    pos: [sx, sy],
+                },
+            };
+            glyphs.insert(c, glyph);
+        }
+        Self {
+            line_height: size[1],
+            glyphs,
+            missing_advance: size[0],
+            kerning: HashMap::new(),
+        }
+    }
+    /// Trims the empty columns around each glyph of a monospaced built-in font
+    ///
+    /// Glyphs keep a pixel of space after them, the space is as wide as the
+    /// narrowest digit so numbers still line up with each other.
+    fn proportional(&self, sheet: &image::RgbaImage) -> Self {
+        let mut font = self.clone();
+        for glyph in font.glyphs.values_mut() {
+            let GlyphSource::Page { pos: [sx, sy], .. } = &mut glyph.source else {
+                continue;
+            };
+            let [w, h] = glyph.size;
+            let inked = |x: u32| (0..h).any(|y| sheet.get_pixel(*sx + x, *sy + y)[3] > 0);
+            let (Some(left), Some(right)) =
+                ((0..w).find(|&x| inked(x)), (0..w).rfind(|&x| inked(x)))
+            else {
+                continue;
+            };
+            *sx += left;
+            glyph.size[0] = right - left + 1;
+            glyph.advance = glyph.size[0] + 1;
+        }
+        let digits = ('0'..='9').map(|c| font.advance(c)).min();
+        if let (Some(space), Some(digits)) = (font.glyphs.get_mut(&' '), digits) {
+            space.advance = digits;
+        }
+        font.missing_advance = font.space_advance();
+        font
+    }
+    /// Loads a BDF font, or a JSON descriptor of the glyphs in `image`
+    pub fn parse(data: &[u8], image: &[u8]) -> Result<Self> {
+        if data.starts_with(b"STARTFONT") {
+            match std::str::from_utf8(data) {
+                Ok(bdf) => Self::from_bdf(bdf),
+                Err(_) => anyhow::bail!("BDF font isn't valid utf-8"),
+            }
+        } else {
+            let image = image::load_from_memory(image)?.to_rgba8();
+            Self::from_json(data, &image)
+        }
+    }
+    /// Reads the glyph bitmaps of a BDF font, glyphs with no unicode encoding are skipped
+    pub fn from_bdf(bdf: &str) -> Result<Self> {
+        let mut font = Self::default();
+        let mut bbox = [0i32; 4];
+        let mut ascent = None;
+        let mut descent = None;
+        // The char currently being read: encoding, advance, bbx
+        let mut encoding: Option<u32> = None;
+        let mut advance = 0;
+        let mut bbx = [0i32; 4];
+        let mut bitmap: Option<Vec<&str>> = None;
+        let mut glyphs = vec![];
+        for line in bdf.lines() {
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or_default();
+            let numbers: Vec<i32> = words.filter_map(|word| word.parse().ok()).collect();
+            let number = |i: usize| numbers.get(i).copied().unwrap_or(0);
+            if let Some(rows) = &mut bitmap {
+                if keyword == "ENDCHAR" {
+                    glyphs.push((
+                        encoding.take(),
+                        advance,
+                        bbx,
+                        bitmap.take().unwrap_or_default(),
+                    ));
+                } else {
+                    rows.push(keyword);
+                }
+                continue;
+            }
+            match keyword {
+                "FONTBOUNDINGBOX" => bbox = [number(0), number(1), number(2), number(3)],
+                "FONT_ASCENT" => ascent = Some(number(0)),
+                "FONT_DESCENT" => descent = Some(number(0)),
+                "STARTCHAR" => {
+                    encoding = None;
+                    advance = bbox[0];
+                    bbx = bbox;
+                }
+                "ENCODING" => encoding = u32::try_from(number(0)).ok(),
+                "DWIDTH" => advance = number(0),
+                "BBX" => bbx = [number(0), number(1), number(2), number(3)],
+                "BITMAP" => bitmap = Some(vec![]),
+                _ => (),
+            }
+        }
+        let ascent = ascent.unwrap_or(bbox[1].saturating_add(bbox[3]));
+        let descent = descent.unwrap_or(bbox[3].saturating_neg());
+        font.line_height = ascent.saturating_add(descent).max(0) as u32;
+        let max = Spritesheet::PAGE_EXTENT;
+        for (encoding, advance, [w, h, x, y], rows) in glyphs {
+            let Some(c) = encoding.and_then(char::from_u32) else {
+                continue;
+            };
+            let (w, h) = (w.max(0) as u32, h.max(0) as u32);
+            if w > max.width || h > max.height {
+                anyhow::bail!("glyph {c:?} is larger than a sprite page");
+            }
+            let mut image = image::RgbaImage::new(w, h);
+            for (row, hex) in rows.iter().take(h as usize).enumerate() {
+                let bits = (0..hex.len() / 2)
+                    .filter_map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok());
+                for (i, byte) in bits.enumerate() {
+                    for bit in 0..8 {
+                        let col = i as u32 * 8 + bit;
+                        if col < w && byte & (0x80 >> bit) != 0 {
+                            image.put_pixel(col, row as u32, image::Rgba([255; 4]));
+                        }
+                    }
+                }
+            }
+            let glyph = Glyph {
+                size: [w, h],
+                offset: [x, ascent.saturating_sub(y).saturating_sub(h as i32)],
+                advance: advance.max(0) as u32,
+                source: GlyphSource::Bitmap(image),
+            };
+            font.glyphs.insert(c, glyph);
+        }
+        font.missing_advance = font.space_advance();
+        Ok(font)
+    }
+    /// Reads a JSON descriptor of the glyphs in `image`:
+    ///
+    /// ```json
+    /// {
+    ///   "line_height": 12,
+    ///   "glyphs": [{ "char": "é", "rect": [0, 0, 6, 12], "advance": 7, "offset": [0, 0] }],
+    ///   "cell": [6, 12],
+    ///   "chars": "ABCDEFGH"
+    /// }
+    /// ```
+    ///
+    /// `glyphs` lists glyphs one by one, `advance` and `offset` are optional.
+    /// `chars` is a shorthand for a grid of `cell` sized glyphs that fills the
+    /// image left to right, then top to bottom. Either can be left out.
+    /// An optional `"kerning": { "AV": -1 }` adjusts the space between pairs.
+    pub fn from_json(json: &[u8], image: &image::RgbaImage) -> Result<Self> {
+        use serde_json::Value;
+        let json: Value = serde_json::from_slice(json)?;
+        let number = |value: &Value| value.as_i64().map(|n| n as i32);
+        let pair =
+            |value: &Value| -> Option<[i32; 2]> { Some([number(&value[0])?, number(&value[1])?]) };
+        let Some(line_height) = number(&json["line_height"]) else {
+            anyhow::bail!("font is missing `line_height`");
+        };
+        let mut font = Self {
+            line_height: line_height.max(0) as u32,
+            ..Self::default()
+        };
+        let mut insert =
+            |c: char, [x, y, w, h]: [i32; 4], advance: Option<i32>, offset: [i32; 2]| {
+                let [x, y, w, h] = [x, y, w, h].map(|n| n.max(0) as u32);
+                if x + w > image.width() || y + h > image.height() {
+                    anyhow::bail!("glyph {c:?} is outside of the font image");
+                }
+                let bitmap = image::imageops::crop_imm(image, x, y, w, h).to_image();
+                let glyph = Glyph {
+                    size: [w, h],
+                    offset,
+                    advance: advance.unwrap_or(w as i32).max(0) as u32,
+                    source: GlyphSource::Bitmap(bitmap),
+                };
+                font.glyphs.insert(c, glyph);
+                Ok(())
+            };
+        if let Some(chars) = json["chars"].as_str() {
+            let Some([cw, ch]) = pair(&json["cell"]).filter(|&[w, h]| w > 0 && h > 0) else {
+                anyhow::bail!("font `chars` need a `cell` size");
+            };
+            let columns = (image.width() as i32 / cw).max(1);
+            for (i, c) in chars.chars().enumerate() {
+                let (col, row) = (i as i32 % columns, i as i32 / columns);
+                insert(c, [col * cw, row * ch, cw, ch], None, [0, 0])?;
+            }
+        }
+        for glyph in json["glyphs"].as_array().into_iter().flatten() {
+            let mut chars = glyph["char"].as_str().unwrap_or_default().chars();
+            let (Some(c), None) = (chars.next(), chars.next()) else {
+                anyhow::bail!("font glyphs need a single `char`");
+            };
+            let rect: Option<Vec<i32>> = glyph["rect"]
+                .as_array()
+                .map(|rect| rect.iter().filter_map(number).collect());
+            let Some(&[x, y, w, h]) = rect.as_deref() else {
+                anyhow::bail!("glyph {c:?} needs a `rect` of [x, y, w, h]");
+            };
+            let offset = pair(&glyph["offset"]).unwrap_or([0, 0]);
+            insert(c, [x, y, w, h], number(&glyph["advance"]), offset)?;
+        }
+        for (pair, amount) in json["kerning"].as_object().into_iter().flatten() {
+            let mut chars = pair.chars();
+            let (Some(a), Some(b), None) = (chars.next(), chars.next(), chars.next()) else {
+                anyhow::bail!("kerning pair {pair:?} needs exactly two chars");
+            };
+            let Some(amount) = number(amount) else {
+                anyhow::bail!("kerning pair {pair:?} needs a number");
+            };
+            font.kerning.insert((a, b), amount);
+        }
+        font.missing_advance = font.space_advance();
+        Ok(font)
+    }
+    fn space_advance(&self) -> u32 {
+        match self.glyphs.get(&' ') {
+            Some(space) => space.advance,
+            None => self.line_height / 2,
+        }
+    }
+    pub fn advance(&self, c: char) -> u32 {
+        match self.glyphs.get(&c) {
+            Some(glyph) => glyph.advance,
+            None => self.missing_advance,
+        }
+    }
+    pub fn kerning(&self, a: char, b: char) -> i32 {
+        self.kerning.get(&(a, b)).copied().unwrap_or(0)
+    }
+    /// How far the pen moves after `c` when `next` follows it
+    fn pen_advance(&self, c: char, next: Option<char>) -> i32 {
+        let kerning = next.map_or(0, |next| self.kerning(c, next));
+        self.advance(c) as i32 + kerning
+    }
+    /// Pen position of every glyph in `text`, `\n` starts a new line
+    pub fn layout(&self, x: i32, y: i32, text: &str) -> Vec<(char, [i32; 2])> {
+        let mut pen = [x, y];
+        let mut glyphs = Vec::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\n' {
+                pen = [x, pen[1] + self.line_height as i32];
+            } else {
+                glyphs.push((c, pen));
+                pen[0] += self.pen_advance(c, chars.peek().copied());
+            }
+        }
+        glyphs
+    }
+    fn measure_line(&self, line: &str) -> u32 {
+        let mut chars = line.chars().peekable();
+        let mut width = 0;
+        while let Some(c) = chars.next() {
+            width += self.pen_advance(c, chars.peek().copied());
+        }
+        width.max(0) as u32
+    }
+    /// Size of `text` as `push_text` would draw it
+    pub fn measure(&self, text: &str) -> [u32; 2] {
+        let width = text.split('\n').map(|line| self.measure_line(line)).max();
+        let lines = text.split('\n').count() as u32;
+        [width.unwrap_or(0), lines * self.line_height]
+    }
+}
+
+/// Packs the glyphs of loaded fonts into the font page, below the built-in fonts
+///
+/// Glyphs are only packed once they're drawn, so big fonts like CJK ones only
+/// take up space for the characters a cart actually uses. When the atlas
+/// fills up, it starts over on the next frame.
+#[derive(Debug, Default)]
+pub struct GlyphAtlas {
+    slots: HashMap<(u32, char), [u32; 2]>,
+    /// x, y and height of the row being filled
+    shelf: [u32; 3],
+    full: bool,
+    /// Set when the cart loads its own font page, which nothing gets packed over
+    page_replaced: bool,
+    writes: Vec<PageWrite>,
+}
+impl GlyphAtlas {
+    const TOP: u32 = Spritesheet::FONT_ROWS;
+    /// Where a glyph is on the font page, packing it if needed
+    pub fn slot(&mut self, font_id: u32, c: char, image: &image::RgbaImage) -> Option<[u32; 2]> {
+        if let Some(&pos) = self.slots.get(&(font_id, c)) {
+            return Some(pos);
+        }
+        let page = Spritesheet::PAGE_EXTENT;
+        // Keep a pixel between glyphs so they never bleed into each other
+        let (w, h) = (image.width() + 1, image.height() + 1);
+        // Glyphs that could never fit shouldn't make the atlas start over
+        if self.page_replaced || w > page.width || h > page.height - Self::TOP {
+            return None;
+        }
+        let [mut x, mut y, mut shelf_h] = self.shelf;
+        if y == 0 {
+            y = Self::TOP;
+        }
+        if x + w > page.width {
+            (x, y, shelf_h) = (0, y + shelf_h, 0);
+        }
+        if self.full || y + h > page.height {
+            self.full = true;
+            return None;
+        }
+        self.shelf = [x + w, y, shelf_h.max(h)];
+        self.slots.insert((font_id, c), [x, y]);
+        self.writes.push(PageWrite::Region {
+            page: Spritesheet::FONT_PAGE,
+            origin: [x, y],
+            image: image.clone(),
+        });
+        Some([x, y])
+    }
+    /// Starts over if the atlas filled up during the last frame
+    pub fn begin_frame(&mut self) {
+        if self.full {
+            self.clear();
+        }
+    }
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.shelf = [0; 3];
+        self.full = false;
+    }
+    /// Stops packing glyphs for good, the cart has loaded its own font page
+    pub fn replace_page(&mut self) {
+        self.clear();
+        self.writes.clear();
+        self.page_replaced = true;
+    }
+    /// Gives the page up like `replace_page` if the cart drew over rows glyphs are packed in
+    pub fn overwrite_rows(&mut self, rows: std::ops::Range<u32>) {
+        if !rows.is_empty() && rows.end > Self::TOP && rows.start < Spritesheet::PAGE_EXTENT.height
+        {
+            self.replace_page();
+        }
+    }
+    /// Glyphs that were packed since the last call
+    pub fn take_writes(&mut self) -> Vec<PageWrite> {
+        std::mem::take(&mut self.writes)
+    }
+}
+
+/// The fonts a cart can draw with, by id
+#[derive(Debug, Default)]
+pub struct Fonts {
+    loaded: HashMap<u32, Font>,
+    pub atlas: GlyphAtlas,
+}
+impl Fonts {
+    /// Drawn in place of fonts that don't exist, like every unknown id used to be
+    pub const FALLBACK: u32 = 1;
+    /// The id text drawn with `id` actually uses
+    pub fn resolve(&self, id: u32) -> u32 {
+        match self.loaded.contains_key(&id) || Font::builtin(id).is_some() {
+            true => id,
+            false => Self::FALLBACK,
+        }
+    }
+    /// Loaded fonts take precedence over built-in fonts with the same id, ids
+    /// that are neither get `FALLBACK`
+    pub fn get(&self, id: u32) -> &Font {
+        match self.loaded.get(&id).or_else(|| Font::builtin(id)) {
+            Some(font) => font,
+            None => self.get(Self::FALLBACK),
+        }
+    }
+    pub fn insert(&mut self, id: u32, font: Font) {
+        self.loaded.insert(id, font);
+        // Glyphs packed for a previous font with this id would be stale
+        self.atlas.clear();
+    }
+    /// Pushes a quad per glyph
+    pub fn push_text(
+        &mut self,
+        quads: &mut Vec<QuadVertex>,
+        id: u32,
+        x: i32,
+        y: i32,
+        color: u32,
+        text: &str,
+    ) {
+        let id = self.resolve(id);
+        // Not `get`, which would borrow the atlas too
+        let Some(font) = self.loaded.get(&id).or_else(|| Font::builtin(id)) else {
+            return;
+        };
+        for (c, pen) in font.layout(x, y, text) {
+            let Some(glyph) = font.glyphs.get(&c) else {
+                continue;
+            };
+            let (page, pos) = match &glyph.source {
+                GlyphSource::Page { page, pos } => (*page, *pos),
+                GlyphSource::Bitmap(image) => match self.atlas.slot(id, c, image) {
+                    Some(pos) => (Spritesheet::FONT_PAGE, pos),
+                    None => continue,
+                },
+            };
+            quads.push(glyph_quad(glyph, page, pos, pen, color));
+        }
+    }
+}
+
+fn glyph_quad(
+    glyph: &Glyph,
+    page: u32,
+    [sx, sy]: [u32; 2],
+    [x, y]: [i32; 2],
+    color: u32,
+) -> QuadVertex {
+    let [w, h] = glyph.size.map(|n| n as f32);
+    let [ox, oy] = glyph.offset;
+    let mut quad = QuadVertex::new([(x + ox) as f32, (y + oy) as f32, w, h])
+        .tex_rect([sx as f32, sy as f32, w, h]);
+    quad.tex_fill = color;
+    quad.page = page;
+    quad
+}
+
+/// Lays out `text` with one of the built-in fonts and pushes a quad per glyph
+pub fn push_text(quads: &mut Vec<QuadVertex>, x: i32, y: i32, font: u8, color: u32, text: &str) {
+    let Some(font) = Font::builtin(font as u32) else {
+        return;
+    };
+    for (c, pen) in font.layout(x, y, text) {
+        if let Some(glyph) = font.glyphs.get(&c) {
+            if let GlyphSource::Page { page, pos } = glyph.source {
+                quads.push(glyph_quad(glyph, page, pos, pen, color));
+            }
+        }
+    }
+}
+
+/// Horizontal alignment of each line inside a text box
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// Wrapping and alignment options for `TextBox::layout`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TextBox {
+    /// Lines wrap at this width, words that don't fit on their own are split
+    pub width: u32,
+    /// Lines that don't fit in this height are dropped, `None` keeps them all
+    pub height: Option<u32>,
+    pub align: Align,
+    /// Extra pixels between lines, can be negative
+    pub line_spacing: i32,
+    /// End the last visible line with "..." when lines are dropped
+    pub ellipsis: bool,
+}
+
+/// A line of a laid out text box, positioned relative to the box's top-left
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub x: i32,
+    pub y: i32,
+    pub text: String,
+}
+
+impl TextBox {
+    pub const ALIGN_CENTER: u32 = 1;
+    pub const ALIGN_RIGHT: u32 = 2;
+    pub const ELLIPSIS: u32 = 4;
+
+    /// Unpacks the `flags` argument of `draw_text_box`
+    pub fn from_flags(width: u32, height: u32, line_spacing: i32, flags: u32) -> Self {
+        let align = match flags & 3 {
+            Self::ALIGN_CENTER => Align::Center,
+            Self::ALIGN_RIGHT => Align::Right,
+            _ => Align::Left,
+        };
+        Self {
+            width,
+            height: (height > 0).then_some(height),
+            align,
+            line_spacing,
+            ellipsis: flags & Self::ELLIPSIS != 0,
+        }
+    }
+    pub fn layout(&self, font: &Font, text: &str) -> Vec<Line> {
+        let line_height = font.line_height as i32;
+        let mut lines = wrap(font, text, self.width);
+        if let Some(height) = self.height {
+            // The last line doesn't need spacing below it
+            let pitch = (line_height + self.line_spacing).max(1);
+            let fits = ((height as i32 - line_height) / pitch + 1).max(0) as usize;
+            if lines.len() > fits {
+                lines.truncate(fits);
+                if self.ellipsis {
+                    if let Some(last) = lines.last_mut() {
+                        *last = with_ellipsis(font, last, self.width);
+                    }
+                }
+            }
+        }
+        lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let free = self.width as i32 - font.measure_line(&text) as i32;
+                let x = match self.align {
+                    Align::Left => 0,
+                    Align::Center => free / 2,
+                    Align::Right => free,
+                };
+                let y = i as i32 * (line_height + self.line_spacing);
+                Line { x, y, text }
+            })
+            .collect()
+    }
+}
+
+/// Splits text into lines no wider than `width`, breaking at spaces when possible
+fn wrap(font: &Font, text: &str, width: u32) -> Vec<String> {
+    let mut lines = vec![];
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut line_width = 0;
+        for word in paragraph.split(' ') {
+            let word_width = font.measure_line(word);
+            let space = if line.is_empty() {
+                0
+            } else {
+                font.advance(' ')
+            };
+            if line_width + space + word_width <= width {
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.push_str(word);
+                line_width += space + word_width;
+                continue;
+            }
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+            }
+            // Split words that are wider than the box on their own
+            for c in word.chars() {
+                let w = font.advance(c);
+                if line_width + w > width && !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                    line_width = 0;
+                }
+                line.push(c);
+                line_width += w;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Shortens `line` until it fits in `width` with "..." on the end
+fn with_ellipsis(font: &Font, line: &str, width: u32) -> String {
+    const ELLIPSIS: &str = "...";
+    let ellipsis_width = font.measure_line(ELLIPSIS);
+    let mut line = line.trim_end().to_string();
+    while !line.is_empty() && font.measure_line(&line) + ellipsis_width > width {
+        line.pop();
+        line.truncate(line.trim_end().len());
+    }
+    line.push_str(ELLIPSIS);
+    line
+}
+
+fn get_glyph_coords(font: u8, c: char) -> (u32, u32, u32, u32) {
+    let (sw, sh) = match font {
+        0 => (5, 5),
+        1 => (5, 8),
+        2 => (8, 8),
+        _ => (5, 8),
+    };
+    let (ox, oy) = match font {
+        0 => (0, 0),
+        1 => (0, 32),
+        2 => (0, 80),
+        _ => (0, 32),
+    };
+    let (sx, sy) = match c {
+        ' ' | '\n' | '\t' => (0, 0),
+        '!' => (1, 0),
+        '"' => (2, 0),
+        '#' => (3, 0),
+        '$' => (4, 0),
+        '%' => (5, 0),
+        '&' => (6, 0),
+        '\'' | '’' => (7, 0),
+        '(' => (8, 0),
+        ')' => (9, 0),
+        '*' => (10, 0),
+        '+' => (11, 0),
+        ',' => (12, 0),
+        '-' => (13, 0),
+        '.' => (14, 0),
+        '/' => (15, 0),
+        '0' => (0, 1),
+        '1' => (1, 1),
+        '2' => (2, 1),
+        '3' => (3, 1),
+        '4' => (4, 1),
+        '5' => (5, 1),
+        '6' => (6, 1),
+        '7' => (7, 1),
+        '8' => (8, 1),
+        '9' => (9, 1),
+        ':' => (10, 1),
+        ';' => (11, 1),
+        '<' => (12, 1),
+        '=' => (13, 1),
+        '>' => (14, 1),
+        '?' => (15, 1),
+        '@' => (0, 2),
+        'A' => (1, 2),
+        'B' => (2, 2),
+        'C' => (3, 2),
+        'D' => (4, 2),
+        'E' => (5, 2),
+        'F' => (6, 2),
+        'G' => (7, 2),
+        'H' => (8, 2),
+        'I' => (9, 2),
+        'J' => (10, 2),
+        'K' => (11, 2),
+        'L' => (12, 2),
+        'M' => (13, 2),
+        'N' => (14, 2),
+        'O' => (15, 2),
+        'P' => (0, 3),
+        'Q' => (1, 3),
+        'R' => (2, 3),
+        'S' => (3, 3),
+        'T' => (4, 3),
+        'U' => (5, 3),
+        'V' => (6, 3),
+        'W' => (7, 3),
+        'X' => (8, 3),
+        'Y' => (9, 3),
+        'Z' => (10, 3),
+        '[' => (11, 3),
+        '\\' => (12, 3),
+        ']' => (13, 3),
+        '^' => (14, 3),
+        '_' => (15, 3),
+        '`' => (0, 4),
+        'a' => (1, 4),
+        'b' => (2, 4),
+        'c' => (3, 4),
+        'd' => (4, 4),
+        'e' => (5, 4),
+        'f' => (6, 4),
+        'g' => (7, 4),
+        'h' => (8, 4),
+        'i' => (9, 4),
+        'j' => (10, 4),
+        'k' => (11, 4),
+        'l' => (12, 4),
+        'm' => (13, 4),
+        'n' => (14, 4),
+        'o' => (15, 4),
+        'p' => (0, 5),
+        'q' => (1, 5),
+        'r' => (2, 5),
+        's' => (3, 5),
+        't' => (4, 5),
+        'u' => (5, 5),
+        'v' => (6, 5),
+        'w' => (7, 5),
+        'x' => (8, 5),
+        'y' => (9, 5),
+        'z' => (10, 5),
+        '{' => (11, 5),
+        '|' => (12, 5),
+        '}' => (13, 5),
+        '~' => (14, 5),
+        '🏠' => (15, 5),
+        _ => (0, 0),
+    };
+    (ox + (sx * sw), oy + (sy * sh), sw, sh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every char is 4 pixels wide, the font has no glyphs of its own
+    fn mono() -> Font {
+        Font {
+            line_height: 8,
+            missing_advance: 4,
+            ..Font::default()
+        }
+    }
+
+    const BDF: &str = "STARTFONT 2.1
+FONTBOUNDINGBOX 4 6 0 -1
+FONT_ASCENT 5
+FONT_DESCENT 1
+CHARS 3
+STARTCHAR A
+ENCODING 65
+DWIDTH 5 0
+BBX 3 2 1 0
+BITMAP
+A0
+40
+ENDCHAR
+STARTCHAR unencoded
+ENCODING -1
+BBX 1 1 0 0
+BITMAP
+80
+ENDCHAR
+STARTCHAR space
+ENCODING 32
+DWIDTH 3 0
+BBX 0 0 0 0
+BITMAP
+ENDCHAR
+ENDFONT
+";
+
+    #[test]
+    fn reads_bdf_fonts() {
+        let font = Font::parse(BDF.as_bytes(), &[]).unwrap();
+        assert_eq!(font.line_height, 6);
+        assert_eq!(font.glyphs.len(), 2);
+        let glyph = &font.glyphs[&'A'];
+        assert_eq!(glyph.size, [3, 2]);
+        // Sits on the baseline, 5 pixels below the top of the line
+        assert_eq!(glyph.offset, [1, 3]);
+        assert_eq!(glyph.advance, 5);
+        let GlyphSource::Bitmap(image) = &glyph.source else {
+            panic!("BDF glyphs are bitmaps");
+        };
+        let inked: Vec<bool> = image.pixels().map(|pixel| pixel[3] > 0).collect();
+        assert_eq!(inked, [true, false, true, false, true, false]);
+        assert_eq!(font.missing_advance, 3);
+    }
+
+    #[test]
+    fn rejects_bdf_glyphs_larger_than_a_page() {
+        let bdf = BDF.replace("BBX 3 2 1 0", "BBX 100000 2 1 0");
+        assert!(Font::from_bdf(&bdf).is_err());
+    }
+
+    #[test]
+    fn bdf_metrics_dont_overflow() {
+        let bdf = BDF
+            .replace("FONT_ASCENT 5", "FONT_ASCENT 2147483647")
+            .replace("FONT_DESCENT 1", "FONT_DESCENT 2147483647")
+            .replace("BBX 3 2 1 0", "BBX 3 2 1 -2147483648");
+        let font = Font::from_bdf(&bdf).unwrap();
+        assert_eq!(font.line_height, i32::MAX as u32);
+        assert_eq!(font.glyphs[&'A'].offset, [1, i32::MAX - 2]);
+    }
+
+    #[test]
+    fn reads_json_fonts() {
+        let image = image::RgbaImage::new(16, 8);
+        let json = br#"{
+            "line_height": 8,
+            "cell": [4, 8],
+            "chars": "abcd",
+            "glyphs": [{ "char": "e", "rect": [8, 0, 8, 8], "advance": 9, "offset": [0, -1] }],
+            "kerning": { "ab": -1 }
+        }"#;
+        let font = Font::from_json(json, &image).unwrap();
+        assert_eq!(font.line_height, 8);
+        assert_eq!(font.glyphs.len(), 5);
+        assert_eq!(font.glyphs[&'b'].size, [4, 8]);
+        assert_eq!(font.glyphs[&'b'].advance, 4);
+        assert_eq!(font.glyphs[&'e'].size, [8, 8]);
+        assert_eq!(font.glyphs[&'e'].advance, 9);
+        assert_eq!(font.glyphs[&'e'].offset, [0, -1]);
+        assert_eq!(font.kerning('a', 'b'), -1);
+        // No space glyph, so missing chars are half as wide as a line is tall
+        assert_eq!(font.missing_advance, 4);
+    }
+
+    #[test]
+    fn rejects_bad_json_fonts() {
+        let image = image::RgbaImage::new(16, 8);
+        let bad: [&[u8]; 6] = [
+            br#"{}"#,
+            br#"{"line_height": 8, "chars": "a"}"#,
+            br#"{"line_height": 8, "glyphs": [{"char": "ab", "rect": [0, 0, 1, 1]}]}"#,
+            br#"{"line_height": 8, "glyphs": [{"char": "a", "rect": [0, 0, 1]}]}"#,
+            br#"{"line_height": 8, "glyphs": [{"char": "a", "rect": [12, 0, 8, 8]}]}"#,
+            br#"{"line_height": 8, "kerning": {"abc": 1}}"#,
+        ];
+        for json in bad {
+            assert!(Font::from_json(json, &image).is_err());
+        }
+    }
+
+    #[test]
+    fn atlas_packs_glyphs_below_the_builtin_fonts() {
+        let mut atlas = GlyphAtlas::default();
+        let image = image::RgbaImage::new(7, 9);
+        assert_eq!(atlas.slot(0, 'a', &image), Some([0, GlyphAtlas::TOP]));
+        assert_eq!(atlas.slot(0, 'b', &image), Some([8, GlyphAtlas::TOP]));
+        assert_eq!(atlas.slot(0, 'a', &image), Some([0, GlyphAtlas::TOP]));
+        assert_eq!(atlas.take_writes().len(), 2);
+        // Too big to ever fit, but the packed glyphs stay
+        assert_eq!(atlas.slot(0, 'c', &image::RgbaImage::new(256, 1)), None);
+        atlas.begin_frame();
+        assert_eq!(atlas.slot(0, 'b', &image), Some([8, GlyphAtlas::TOP]));
+    }
+
+    #[test]
+    fn atlas_stops_packing_once_the_font_page_is_replaced() {
+        let mut atlas = GlyphAtlas::default();
+        let image = image::RgbaImage::new(7, 9);
+        atlas.slot(0, 'a', &image);
+        atlas.replace_page();
+        assert!(atlas.take_writes().is_empty());
+        assert_eq!(atlas.slot(0, 'a', &image), None);
+        atlas.clear();
+        atlas.begin_frame();
+        assert_eq!(atlas.slot(1, 'a', &image), None);
+    }
+
+    #[test]
+    fn atlas_gives_the_page_up_when_its_rows_are_drawn_over() {
+        let mut atlas = GlyphAtlas::default();
+        let image = image::RgbaImage::new(7, 9);
+        atlas.slot(0, 'a', &image);
+        // The built-in fonts and rows outside of the page don't matter
+        atlas.overwrite_rows(0..GlyphAtlas::TOP);
+        atlas.overwrite_rows(GlyphAtlas::TOP..GlyphAtlas::TOP);
+        atlas.overwrite_rows(Spritesheet::PAGE_EXTENT.height..u32::MAX);
+        assert_eq!(atlas.slot(0, 'a', &image), Some([0, GlyphAtlas::TOP]));
+        atlas.overwrite_rows(GlyphAtlas::TOP - 1..GlyphAtlas::TOP + 1);
+        assert!(atlas.take_writes().is_empty());
+        assert_eq!(atlas.slot(0, 'a', &image), None);
+    }
+
+    #[test]
+    fn unknown_font_ids_fall_back_to_the_medium_font() {
+        let fonts = Fonts::default();
+        assert_eq!(fonts.resolve(99), Fonts::FALLBACK);
+        assert_eq!(fonts.resolve(4), 4);
+        let text = "Hello, world!\nMore text";
+        assert_eq!(fonts.get(99).measure(text), fonts.get(1).measure(text));
+        assert_ne!(fonts.get(99).measure(text), fonts.get(0).measure(text));
+    }
+
+    #[test]
+    fn measures_lines_with_kerning() {
+        let mut font = mono();
+        assert_eq!(font.measure("ab\nabc"), [12, 16]);
+        font.kerning.insert(('a', 'b'), -1);
+        assert_eq!(font.measure("ab"), [7, 8]);
+        assert_eq!(
+            font.layout(0, 0, "ab\nb"),
+            vec![('a', [0, 0]), ('b', [3, 0]), ('b', [0, 8])]
+        );
+    }
+
+    #[test]
+    fn wraps_at_spaces() {
+        let font = mono();
+        assert_eq!(wrap(&font, "aaa bb cc", 20), vec!["aaa", "bb cc"]);
+        assert_eq!(wrap(&font, "a\n\nb", 20), vec!["a", "", "b"]);
+        assert_eq!(wrap(&font, "", 20), vec![""]);
+    }
+
+    #[test]
+    fn splits_words_wider_than_the_box() {
+        let font = mono();
+        assert_eq!(wrap(&font, "abcdefgh", 12), vec!["abc", "def", "gh"]);
+        assert_eq!(wrap(&font, "a bcdefg", 12), vec!["a", "bcd", "efg"]);
+        // Every line keeps at least one char, even if it doesn't fit
+        assert_eq!(wrap(&font, "ab", 2), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn ellipsis_fits_in_the_width() {
+        let font = mono();
+        assert_eq!(with_ellipsis(&font, "hello world", 24), "hel...");
+        // Trailing spaces are trimmed before the dots
+        assert_eq!(with_ellipsis(&font, "ab cd", 20), "ab...");
+        assert_eq!(with_ellipsis(&font, "abc", 4), "...");
+    }
+
+    #[test]
+    fn text_box_drops_lines_that_dont_fit() {
+        let font = mono();
+        let text_box = TextBox::from_flags(20, 16, 0, TextBox::ELLIPSIS);
+        let lines = text_box.layout(&font, "aa bb cc dd ee");
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, vec!["aa bb", "cc..."]);
+        let text_box = TextBox::from_flags(20, 16, 0, 0);
+        assert_eq!(text_box.layout(&font, "aa bb cc dd ee")[1].text, "cc dd");
+    }
+
+    #[test]
+    fn text_box_aligns_and_spaces_lines() {
+        let font = mono();
+        let text_box = TextBox::from_flags(20, 0, 2, TextBox::ALIGN_RIGHT);
+        let line = |x, y, text: &str| Line {
+            x,
+            y,
+            text: text.to_string(),
+        };
+        assert_eq!(
+            text_box.layout(&font, "a\nbb"),
+            vec![line(16, 0, "a"), line(12, 10, "bb")]
+        );
+        let text_box = TextBox::from_flags(20, 0, 0, TextBox::ALIGN_CENTER);
+        assert_eq!(text_box.layout(&font, "bb"), vec![line(6, 0, "bb")]);
+    }
+}
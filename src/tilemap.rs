@@ -0,0 +1,282 @@
+use anyhow::Result;
+
+use crate::gpu::{QuadVertex, Spritesheet};
+
+/// A grid of tiles drawn from one spritesheet page
+///
+/// Tile `0` is empty and tile `n` is the `n - 1`th tile on the page, counting
+/// left to right and then top to bottom, which matches Tiled's global ids
+/// when the map uses a single tileset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tilemap {
+    pub width: u32,
+    pub height: u32,
+    pub tile_size: [u32; 2],
+    pub page: u32,
+    pub tiles: Vec<u32>,
+}
+impl Tilemap {
+    /// Magic bytes at the start of the binary map format
+    pub const MAGIC: &'static [u8; 4] = b"GBTM";
+    pub fn new(width: u32, height: u32, tile_size: [u32; 2], page: u32) -> Result<Self> {
+        let [tw, th] = tile_size;
+        let page_size = Spritesheet::PAGE_EXTENT;
+        if tw == 0 || th == 0 || tw > page_size.width || th > page_size.height {
+            anyhow::bail!("invalid tile size {tw}x{th}");
+        }
+        if page >= Spritesheet::PAGES {
+            anyhow::bail!("sprite page {page} doesn't exist");
+        }
+        let Some(len) = width.checked_mul(height).filter(|&len| len <= 1 << 24) else {
+            anyhow::bail!("tilemap of {width}x{height} tiles is too large");
+        };
+        Ok(Self {
+            width,
+            height,
+            tile_size,
+            page,
+            tiles: vec![0; len as usize],
+        })
+    }
+    /// Loads either the binary format or a Tiled JSON map
+    pub fn parse(bytes: &[u8], layer: u32, page: u32) -> Result<Self> {
+        if bytes.starts_with(Self::MAGIC) {
+            Self::from_binary(bytes, page)
+        } else {
+            Self::from_tiled_json(bytes, layer, page)
+        }
+    }
+    /// Reads the binary format, every field is a little-endian u32:
+    ///
+    /// `"GBTM"`, version (1), width, height, tile width, tile height, then
+    /// `width * height` tiles row by row
+    pub fn from_binary(bytes: &[u8], page: u32) -> Result<Self> {
+        let mut words = bytes
+            .get(Self::MAGIC.len()..)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        let mut header = [0; 5];
+        for field in &mut header {
+            match words.next() {
+                Some(word) => *field = word,
+                None => anyhow::bail!("tilemap header is truncated"),
+            }
+        }
+        let [version, width, height, tw, th] = header;
+        if version != 1 {
+            anyhow::bail!("unsupported tilemap version {version}");
+        }
+        let mut map = Self::new(width, height, [tw, th], page)?;
+        let len = map.tiles.len();
+        map.tiles.splice(.., words.take(len));
+        if map.tiles.len() != len {
+            anyhow::bail!("tilemap has {} tiles, expected {len}", map.tiles.len());
+        }
+        Ok(map)
+    }
+    /// Reads the `layer`th tile layer of a map exported from Tiled as JSON
+    ///
+    /// Only uncompressed layer data is supported, and flip flags are ignored.
+    pub fn from_tiled_json(bytes: &[u8], layer: u32, page: u32) -> Result<Self> {
+        use serde_json::Value;
+        let json: Value = serde_json::from_slice(bytes)?;
+        let number = |value: &Value, key: &str| -> Result<u32> {
+            match value[key].as_u64().and_then(|n| u32::try_from(n).ok()) {
+                Some(n) => Ok(n),
+                None => anyhow::bail!("tiled map is missing `{key}`"),
+            }
+        };
+        let tile_size = [number(&json, "tilewidth")?, number(&json, "tileheight")?];
+        // Global ids are offset by the first tileset's `firstgid`
+        let first_gid = match json["tilesets"].get(0) {
+            Some(tileset) => number(tileset, "firstgid")?,
+            None => 1,
+        };
+        let Some(layers) = json["layers"].as_array() else {
+            anyhow::bail!("tiled map has no layers");
+        };
+        let Some(layer) = layers
+            .iter()
+            .filter(|l| l["type"] == "tilelayer")
+            .nth(layer as usize)
+        else {
+            anyhow::bail!("tiled map has no tile layer {layer}");
+        };
+        if !matches!(layer["encoding"].as_str(), None | Some("csv")) {
+            anyhow::bail!("tiled layers must be saved without compression");
+        }
+        let Some(data) = layer["data"].as_array() else {
+            anyhow::bail!("tiled layer has no tile data");
+        };
+        let mut map = Self::new(
+            number(layer, "width")?,
+            number(layer, "height")?,
+            tile_size,
+            page,
+        )?;
+        if data.len() != map.tiles.len() {
+            anyhow::bail!(
+                "tiled layer has {} tiles, expected {}",
+                data.len(),
+                map.tiles.len()
+            );
+        }
+        for (tile, gid) in map.tiles.iter_mut().zip(data) {
+            // The top bits of a gid are flip flags
+            let gid = gid.as_u64().unwrap_or(0) as u32 & 0x1fff_ffff;
+            *tile = if gid == 0 {
+                0
+            } else {
+                gid + 1 - first_gid.min(gid)
+            };
+        }
+        Ok(map)
+    }
+    pub fn get(&self, x: u32, y: u32) -> Option<u32> {
+        if x < self.width && y < self.height {
+            self.tiles.get((y * self.width + x) as usize).copied()
+        } else {
+            None
+        }
+    }
+    pub fn set(&mut self, x: u32, y: u32, tile: u32) -> bool {
+        if x < self.width && y < self.height {
+            self.tiles[(y * self.width + x) as usize] = tile;
+            true
+        } else {
+            false
+        }
+    }
+    /// Pushes a quad for every non-empty tile that overlaps `view`
+    ///
+    /// The map's top-left corner is drawn at `pos - scroll`. `view` holds the
+    /// min and max corners of the visible area (x0, y0, x1, y1) in the same space.
+    pub fn push_quads(
+        &self,
+        quads: &mut Vec<QuadVertex>,
+        pos: [f32; 2],
+        scroll: [f32; 2],
+        view: [f32; 4],
+    ) {
+        let [tw, th] = self.tile_size.map(|n| n as f32);
+        let ox = pos[0] - scroll[0];
+        let oy = pos[1] - scroll[1];
+        let [x0, y0, x1, y1] = view;
+        let range = |from: f32, to: f32, size: f32, count: u32| {
+            let start = (from / size).floor().clamp(0., count as f32) as u32;
+            let end = (to / size).ceil().clamp(0., count as f32) as u32;
+            start..end
+        };
+        let columns = Spritesheet::PAGE_EXTENT.width / self.tile_size[0];
+        for ty in range(y0 - oy, y1 - oy, th, self.height) {
+            for tx in range(x0 - ox, x1 - ox, tw, self.width) {
+                let tile = self.tiles[(ty * self.width + tx) as usize];
+                if tile == 0 {
+                    continue;
+                }
+                let index = tile - 1;
+                let sx = (index % columns) as f32 * tw;
+                let sy = (index / columns) as f32 * th;
+                let mut quad = QuadVertex::new([ox + tx as f32 * tw, oy + ty as f32 * th, tw, th])
+                    .tex_rect([sx, sy, tw, th]);
+                quad.page = self.page;
+                quads.push(quad);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(header: [u32; 5], tiles: &[u32]) -> Vec<u8> {
+        let mut bytes = Tilemap::MAGIC.to_vec();
+        for word in header.iter().chain(tiles) {
+            bytes.extend(word.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn reads_the_binary_format() {
+        let map = Tilemap::parse(&binary([1, 3, 2, 8, 8], &[0, 1, 2, 3, 4, 5]), 0, 2).unwrap();
+        assert_eq!([map.width, map.height], [3, 2]);
+        assert_eq!(map.tile_size, [8, 8]);
+        assert_eq!(map.page, 2);
+        assert_eq!(map.get(2, 1), Some(5));
+        assert_eq!(map.get(3, 0), None);
+    }
+
+    #[test]
+    fn rejects_bad_binary_maps() {
+        assert!(Tilemap::from_binary(&binary([2, 1, 1, 8, 8], &[0]), 0).is_err());
+        assert!(Tilemap::from_binary(&binary([1, 2, 2, 8, 8], &[0, 0, 0]), 0).is_err());
+        assert!(Tilemap::from_binary(&binary([1, 1, 1, 0, 8], &[0]), 0).is_err());
+        assert!(Tilemap::from_binary(&binary([1, 1, 1, 8, 8], &[0]), Spritesheet::PAGES).is_err());
+        assert!(Tilemap::from_binary(&binary([1, 1 << 16, 1 << 16, 8, 8], &[]), 0).is_err());
+        assert!(Tilemap::from_binary(b"GBTM\x01\0\0\0", 0).is_err());
+    }
+
+    #[test]
+    fn reads_tiled_json() {
+        let json = br#"{
+            "tilewidth": 16, "tileheight": 8,
+            "tilesets": [{"firstgid": 5}],
+            "layers": [
+                {"type": "objectgroup"},
+                {"type": "tilelayer", "width": 2, "height": 1, "data": [0, 6]},
+                {"type": "tilelayer", "width": 2, "height": 1, "data": [2147483653, 5]}
+            ]
+        }"#;
+        let map = Tilemap::parse(json, 0, 1).unwrap();
+        assert_eq!(map.tile_size, [16, 8]);
+        assert_eq!(map.tiles, vec![0, 2]);
+        // Flip flags are dropped
+        assert_eq!(Tilemap::parse(json, 1, 1).unwrap().tiles, vec![1, 1]);
+        assert!(Tilemap::parse(json, 2, 1).is_err());
+    }
+
+    #[test]
+    fn rejects_compressed_tiled_layers() {
+        let json = br#"{"tilewidth": 8, "tileheight": 8, "layers": [
+            {"type": "tilelayer", "width": 1, "height": 1, "encoding": "base64", "data": "AAAA"}
+        ]}"#;
+        assert!(Tilemap::parse(json, 0, 0).is_err());
+    }
+
+    #[test]
+    fn culls_tiles_outside_the_view() {
+        let mut map = Tilemap::new(10, 10, [8, 8], 0).unwrap();
+        map.tiles.fill(1);
+        let mut quads = vec![];
+        map.push_quads(&mut quads, [0., 0.], [4., 0.], [0., 0., 16., 8.]);
+        // Scrolled by half a tile, so three columns of one row are visible
+        let rects: Vec<[f32; 4]> = quads.iter().map(|quad| quad.rect).collect();
+        assert_eq!(
+            rects,
+            vec![[-4., 0., 8., 8.], [4., 0., 8., 8.], [12., 0., 8., 8.]]
+        );
+        quads.clear();
+        map.push_quads(&mut quads, [100., 100.], [0., 0.], [0., 0., 16., 8.]);
+        assert!(quads.is_empty());
+    }
+
+    #[test]
+    fn empty_tiles_are_skipped_and_ids_map_to_the_page() {
+        let mut map = Tilemap::new(2, 1, [8, 8], 3).unwrap();
+        map.set(1, 0, 34);
+        let mut quads = vec![];
+        map.push_quads(&mut quads, [0., 0.], [0., 0.], [0., 0., 256., 144.]);
+        assert_eq!(quads.len(), 1);
+        let columns = Spritesheet::PAGE_EXTENT.width as f32 / 8.;
+        let (tex_rect, page) = (quads[0].tex_rect, quads[0].page);
+        assert_eq!(
+            tex_rect,
+            [(33. % columns) * 8., (33. / columns).floor() * 8., 8., 8.]
+        );
+        assert_eq!(page, 3);
+        assert!(!map.set(2, 0, 1));
+    }
+}
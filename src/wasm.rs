@@ -2,10 +2,576 @@ use anyhow::Result;
 
 struct HostState {
     pub quads: Vec<crate::gpu::QuadVertex>,
+    pub palette: crate::gpu::Palette,
+    pub palette_dirty: bool,
+    pub palette_mode: bool,
+    pub layer: i32,
+    pub clips: Vec<[f32; 4]>,
+    /// Recorded every time the layer or clip changes
+    pub runs: Vec<DrawRun>,
+    /// Ranges of `quads` that share a clip, in draw order
+    pub batches: Vec<crate::gpu::DrawBatch>,
+    pub transforms: Vec<crate::gpu::Transform>,
+    pub zoom: f32,
+    /// Spritesheet changes waiting to be uploaded
+    pub pages: Vec<crate::gpu::PageWrite>,
+    /// Tilemaps created by the cart, ids are indices
+    pub maps: Vec<crate::tilemap::Tilemap>,
+    pub fonts: crate::text::Fonts,
+    /// CPU copy of the spritesheet for the pixel imports
+    pub pixels: crate::gpu::SpritePixels,
+    /// Offscreen targets created by the cart, target n is at n - 1
+    pub targets: Vec<crate::gpu::TargetState>,
+    /// Where quads are drawn, 0 is the canvas
+    pub target: u32,
+    /// What quads sample, 0 is the spritesheet
+    pub source: u32,
+}
+
+/// A quad as carts lay it out in memory for `draw_quads`, version 1
+///
+/// | offset | type   | field                                             |
+/// |--------|--------|---------------------------------------------------|
+/// | 0      | f32 x4 | x, y, w, h, all finite                            |
+/// | 16     | u32    | fill for rects, tint for sprites (0xAABBGGRR)     |
+/// | 20     | u16 x4 | sx, sy, sw, sh, a zero sw or sh draws a rect      |
+/// | 28     | f32    | finite rotation in radians around the center      |
+/// | 32     | u8     | spritesheet page                                  |
+/// | 33     | u8     | flags: 1 = flip horizontally, 2 = flip vertically |
+/// | 34     | u16    | reserved, must be 0                               |
+///
+/// Everything is little-endian. New fields go in a new version rather than
+/// changing this one, so carts built against it keep working.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct CartQuad {
+    rect: [f32; 4],
+    color: u32,
+    tex_rect: [u16; 4],
+    rotation: f32,
+    page: u8,
+    flags: u8,
+    reserved: u16,
+}
+impl CartQuad {
+    const VERSION: u32 = 1;
+    const FLIP_X: u8 = 1;
+    const FLIP_Y: u8 = 2;
+    /// Converts to a `QuadVertex`, `None` if a field holds a value this version doesn't define
+    ///
+    /// `palette` only applies to rect fills, sprite tints are always rgba like `draw_sprite_ex`.
+    fn to_quad(self, palette: u32) -> Option<crate::gpu::QuadVertex> {
+        use crate::gpu::{QuadVertex, Spritesheet};
+        let known_flags = Self::FLIP_X | Self::FLIP_Y;
+        if self.flags & !known_flags != 0 || self.reserved != 0 {
+            return None;
+        }
+        if self.page as u32 >= Spritesheet::PAGES {
+            return None;
+        }
+        // NaN or infinite positions would reach the shader and cull or smear unpredictably
+        if !self
+            .rect
+            .iter()
+            .chain([&self.rotation])
+            .all(|n| n.is_finite())
+        {
+            return None;
+        }
+        let mut quad = QuadVertex::new(self.rect);
+        let [sx, sy, sw, sh] = self.tex_rect.map(|n| n as f32);
+        if sw == 0. || sh == 0. {
+            quad.fill = self.color;
+            quad.palette = palette;
+        } else {
+            quad = quad.tex_rect([sx, sy, sw, sh]).flip(
+                self.flags & Self::FLIP_X != 0,
+                self.flags & Self::FLIP_Y != 0,
+            );
+            quad.page = self.page as u32;
+            quad.tint = self.color;
+        }
+        quad.rotation_base = self.rotation;
+        Some(quad)
+    }
+}
+
+/// A piece of a `draw_text` string, see `parse_markup`
+#[derive(Clone, Debug, PartialEq)]
+enum Markup {
+    Text(String),
+    /// `None` goes back to the color `draw_text` was called with
+    Color(Option<u32>),
+    /// `None` goes back to the font `draw_text` was called with
+    Font(Option<u32>),
+    /// An 8x8 sprite from the built-in sprite page, by index
+    ///
+    /// Icons keep their own colors, the current color and palette mode don't apply.
+    Icon(u32),
+}
+
+/// Splits the tags out of a `draw_text` string
+///
+/// - `{c:ff0000}` or `{c:ff000080}` switches to an rgb(a) color, `{c}` switches back
+/// - `{f:L}` switches to a font by name (S, M, L, PS, PM, PL) or id, `{f}` switches back
+/// - `{i:3}` draws the 8x8 sprite with that index in place
+/// - `{{` and `}}` are literal braces
+///
+/// Anything else, like an unknown tag or a lone brace, is kept as literal text.
+fn parse_markup(text: &str) -> Vec<Markup> {
+    let mut parts = vec![];
+    let mut literal = String::new();
+    let mut rest = text;
+    while let Some(i) = rest.find(['{', '}']) {
+        literal.push_str(&rest[..i]);
+        let brace = &rest[i..i + 1];
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix(brace) {
+            literal.push_str(brace);
+            rest = after;
+            continue;
+        }
+        let tag = match brace {
+            "{" => rest
+                .find('}')
+                .and_then(|end| Some((parse_tag(&rest[..end])?, end))),
+            _ => None,
+        };
+        // The text after a brace that doesn't start a tag is read as usual
+        let Some((tag, end)) = tag else {
+            literal.push_str(brace);
+            continue;
+        };
+        rest = &rest[end + 1..];
+        if !literal.is_empty() {
+            parts.push(Markup::Text(std::mem::take(&mut literal)));
+        }
+        parts.push(tag);
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        parts.push(Markup::Text(literal));
+    }
+    parts
+}
+
+fn parse_tag(tag: &str) -> Option<Markup> {
+    let (key, value) = match tag.split_once(':') {
+        Some((key, value)) => (key, Some(value)),
+        None => (tag, None),
+    };
+    let markup = match (key, value) {
+        ("c", None) => Markup::Color(None),
+        ("c", Some(hex)) if hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            let color = match (hex.len(), u32::from_str_radix(hex, 16)) {
+                // Colors are stored as 0xAABBGGRR
+                (6, Ok(rgb)) => (rgb << 8 | 0xff).swap_bytes(),
+                (8, Ok(rgba)) => rgba.swap_bytes(),
+                _ => return None,
+            };
+            Markup::Color(Some(color))
+        }
+        ("f", None) => Markup::Font(None),
+        ("f", Some(name)) => Markup::Font(Some(match name {
+            "S" => 0,
+            "M" => 1,
+            "L" => 2,
+            "PS" => 3,
+            "PM" => 4,
+            "PL" => 5,
+            id => id.parse().ok()?,
+        })),
+        ("i", Some(index)) => Markup::Icon(index.parse().ok()?),
+        _ => return None,
+    };
+    Some(markup)
+}
+
+/// Quads from `start` until the next run share a layer and clip rectangle
+#[derive(Clone, Copy, Debug)]
+struct DrawRun {
+    layer: i32,
+    clip: Option<[f32; 4]>,
+    target: u32,
+    source: u32,
+    start: usize,
 }
 impl HostState {
     pub fn new() -> Self {
-        Self { quads: vec![] }
+        Self {
+            quads: vec![],
+            palette: crate::gpu::Palette::DEFAULT,
+            // Make sure a new cart doesn't inherit the previous cart's palette
+            palette_dirty: true,
+            palette_mode: false,
+            layer: 0,
+            clips: vec![],
+            runs: vec![],
+            batches: vec![],
+            transforms: vec![],
+            zoom: 1.,
+            // Undo any pages the previous cart loaded
+            pages: (0..crate::gpu::Spritesheet::PAGES)
+                .map(crate::gpu::PageWrite::Restore)
+                .collect(),
+            maps: vec![],
+            fonts: crate::text::Fonts::default(),
+            pixels: crate::gpu::SpritePixels::default(),
+            targets: vec![],
+            target: 0,
+            source: 0,
+        }
+    }
+    /// Pushes a quad, applying the current transform
+    pub fn push_quad(&mut self, quad: crate::gpu::QuadVertex) {
+        let quad = match self.transforms.last() {
+            Some(transform) => transform.apply_to_quad(&quad),
+            None => quad,
+        };
+        self.quads.push(quad);
+    }
+    /// Maps a point through the current transform
+    pub fn transform_point(&self, point: [f32; 2]) -> [f32; 2] {
+        match self.transforms.last() {
+            Some(transform) => transform.apply(point),
+            None => point,
+        }
+    }
+    /// Pushes rasterized spans, which are already in canvas space
+    pub fn push_spans(&mut self, spans: &[[i32; 4]], fill: u32) {
+        use crate::gpu::QuadVertex;
+        let palette = self.palette_flags(QuadVertex::PALETTE_FILL);
+        for &[x, y, w, h] in spans {
+            let mut quad = QuadVertex::new([x as f32, y as f32, w as f32, h as f32]);
+            quad.fill = fill;
+            quad.palette = palette;
+            self.quads.push(quad);
+        }
+    }
+    /// Draws a line between the centers of two pixels, both ends inclusive
+    pub fn draw_line(&mut self, from: [i32; 2], to: [i32; 2], thickness: u32, fill: u32) {
+        let center = |[x, y]: [i32; 2]| self.transform_point([x as f32 + 0.5, y as f32 + 0.5]);
+        let [x0, y0] = center(from);
+        let [x1, y1] = center(to);
+        if thickness <= 1 {
+            // Pixel-exact, so rasterize on the host instead of drawing a thin rotated quad
+            let spans = crate::raster::line(
+                x0.floor() as i32,
+                y0.floor() as i32,
+                x1.floor() as i32,
+                y1.floor() as i32,
+                self.visible_pixels(),
+            );
+            self.push_spans(&spans, fill);
+            return;
+        }
+        // Thick lines are a rotated quad, one pixel longer so the ends are inclusive
+        let scale = self.transforms.last().map_or(1., |t| t.scale);
+        let (dx, dy) = (x1 - x0, y1 - y0);
+        let w = (dx * dx + dy * dy).sqrt() + scale;
+        let h = thickness as f32 * scale;
+        let (cx, cy) = ((x0 + x1) / 2., (y0 + y1) / 2.);
+        let mut quad = crate::gpu::QuadVertex::new([cx - w / 2., cy - h / 2., w, h]);
+        quad.rotation_base = dy.atan2(dx);
+        quad.fill = fill;
+        quad.palette = self.palette_flags(crate::gpu::QuadVertex::PALETTE_FILL);
+        self.quads.push(quad);
+    }
+    /// Draws a polygon, filled or as a closed 1px outline
+    pub fn draw_poly(&mut self, points: &[[i32; 2]], fill: u32, filled: bool) {
+        if filled {
+            let points: Vec<_> = points
+                .iter()
+                .map(|&[x, y]| self.transform_point([x as f32, y as f32]))
+                .collect();
+            let spans = crate::raster::polygon(&points, self.visible_pixels());
+            self.push_spans(&spans, fill);
+        } else {
+            for (i, &from) in points.iter().enumerate() {
+                let to = points[(i + 1) % points.len()];
+                self.draw_line(from, to, 1, fill);
+            }
+        }
+    }
+    /// Queues a spritesheet change, keeping the CPU copy in sync
+    pub fn write_page(&mut self, write: crate::gpu::PageWrite) {
+        self.pixels.apply(&write);
+        self.pages.push(write);
+    }
+    /// Draws text with a built-in or loaded font, see `Fonts::get` for unknown ids
+    pub fn draw_text(&mut self, font: u32, x: i32, y: i32, color: u32, text: &str) {
+        let mut glyphs = vec![];
+        self.fonts.push_text(&mut glyphs, font, x, y, color, text);
+        let palette = self.palette_flags(crate::gpu::QuadVertex::PALETTE_TEX_FILL);
+        for mut quad in glyphs {
+            quad.palette = palette;
+            self.push_quad(quad);
+        }
+    }
+    /// Draws text with `parse_markup` tags, or only measures it when `draw` is false
+    ///
+    /// Returns the size of the text, lines are as tall as the tallest font or
+    /// icon on them.
+    pub fn draw_markup(
+        &mut self,
+        font: u32,
+        x: i32,
+        y: i32,
+        color: u32,
+        text: &str,
+        draw: bool,
+    ) -> [u32; 2] {
+        use crate::gpu::{QuadVertex, Spritesheet};
+        const ICON_SIZE: u32 = 8;
+        let (mut current_font, mut current_color) = (font, color);
+        let mut line_height = self.fonts.get(font).line_height;
+        let mut pen = [x, y];
+        let mut width = 0;
+        for part in parse_markup(text) {
+            match part {
+                Markup::Color(color_tag) => current_color = color_tag.unwrap_or(color),
+                Markup::Font(font_tag) => {
+                    current_font = font_tag.unwrap_or(font);
+                    line_height = line_height.max(self.fonts.get(current_font).line_height);
+                }
+                Markup::Icon(index) => {
+                    if draw {
+                        let columns = Spritesheet::PAGE_EXTENT.width / ICON_SIZE;
+                        let [sx, sy] = [index % columns, index / columns].map(|n| n * ICON_SIZE);
+                        let size = ICON_SIZE as f32;
+                        let mut quad = QuadVertex::new([pen[0] as f32, pen[1] as f32, size, size])
+                            .tex_rect([sx as f32, sy as f32, size, size]);
+                        quad.page = Spritesheet::SPRITE_PAGE;
+                        // Left untinted and in true color, see `Markup::Icon`
+                        self.push_quad(quad);
+                    }
+                    pen[0] += ICON_SIZE as i32;
+                    line_height = line_height.max(ICON_SIZE);
+                }
+                Markup::Text(text) => {
+                    for (i, line) in text.split('\n').enumerate() {
+                        if i > 0 {
+                            width = width.max(pen[0] - x);
+                            pen = [x, pen[1] + line_height as i32];
+                            line_height = self.fonts.get(current_font).line_height;
+                        }
+                        if draw {
+                            self.draw_text(current_font, pen[0], pen[1], current_color, line);
+                        }
+                        pen[0] += self.fonts.get(current_font).measure(line)[0] as i32;
+                    }
+                }
+            }
+        }
+        width = width.max(pen[0] - x);
+        let height = pen[1] - y + line_height as i32;
+        [width.max(0) as u32, height.max(0) as u32]
+    }
+    /// Part of the current target that can be drawn on, as x0 y0 x1 y1 after transforms
+    pub fn visible_rect(&self) -> [f32; 4] {
+        let (x1, y1) = match self.target {
+            0 => {
+                let [w, h] = crate::gpu::Canvas::SIZE;
+                (w as f32 / self.zoom, h as f32 / self.zoom)
+            }
+            id => {
+                let [w, h] = self.targets[id as usize - 1].size;
+                (w as f32, h as f32)
+            }
+        };
+        match self.clips.last() {
+            Some(&[cx, cy, cw, ch]) => [cx.max(0.), cy.max(0.), x1.min(cx + cw), y1.min(cy + ch)],
+            None => [0., 0., x1, y1],
+        }
+    }
+    /// `visible_rect` grown to whole pixels, as xywh for the rasterizer
+    fn visible_pixels(&self) -> [i32; 4] {
+        let [x0, y0, x1, y1] = self.visible_rect();
+        let (x0, y0) = (x0.floor() as i32, y0.floor() as i32);
+        let (x1, y1) = (x1.ceil() as i32, y1.ceil() as i32);
+        [
+            x0,
+            y0,
+            x1.saturating_sub(x0).max(0),
+            y1.saturating_sub(y0).max(0),
+        ]
+    }
+    /// `visible_rect` before the current transform, the space quads are pushed in
+    ///
+    /// A rotated transform gives the bounding box of the visible area.
+    fn untransformed_visible_rect(&self) -> [f32; 4] {
+        let view = self.visible_rect();
+        let Some(transform) = self.transforms.last() else {
+            return view;
+        };
+        let inverse = transform.inverse();
+        let [x0, y0, x1, y1] = view;
+        let corners = [[x0, y0], [x1, y0], [x0, y1], [x1, y1]].map(|p| inverse.apply(p));
+        corners.iter().fold(
+            [
+                f32::INFINITY,
+                f32::INFINITY,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY,
+            ],
+            |[x0, y0, x1, y1], &[x, y]| [x0.min(x), y0.min(y), x1.max(x), y1.max(y)],
+        )
+    }
+    /// Draws the visible part of a tilemap, returns false if it doesn't exist
+    pub fn draw_map(&mut self, id: u32, pos: [f32; 2], scroll: [f32; 2]) -> bool {
+        let Some(map) = self.maps.get(id as usize) else {
+            return false;
+        };
+        let view = self.untransformed_visible_rect();
+        let mut tiles = vec![];
+        map.push_quads(&mut tiles, pos, scroll, view);
+        for quad in tiles {
+            self.push_quad(quad);
+        }
+        true
+    }
+    pub fn push_transform(&mut self, local: crate::gpu::Transform) {
+        let transform = match self.transforms.last() {
+            Some(parent) => parent.then(&local),
+            None => local,
+        };
+        self.transforms.push(transform);
+    }
+    pub fn set_layer(&mut self, layer: i32) {
+        if layer != self.layer {
+            self.layer = layer;
+            self.start_run();
+        }
+    }
+    /// Clips drawing to a rectangle, nested clips are intersected
+    pub fn push_clip(&mut self, rect: [f32; 4]) {
+        // Clip rects follow the current transform, rotated ones use their bounding box
+        let [x, y, w, h] = rect;
+        let corners = [[x, y], [x + w, y], [x, y + h], [x + w, y + h]];
+        let corners = match self.transforms.last() {
+            Some(transform) => corners.map(|corner| transform.apply(corner)),
+            None => corners,
+        };
+        let x0 = corners.iter().map(|c| c[0]).fold(f32::INFINITY, f32::min);
+        let y0 = corners.iter().map(|c| c[1]).fold(f32::INFINITY, f32::min);
+        let x1 = corners
+            .iter()
+            .map(|c| c[0])
+            .fold(f32::NEG_INFINITY, f32::max);
+        let y1 = corners
+            .iter()
+            .map(|c| c[1])
+            .fold(f32::NEG_INFINITY, f32::max);
+        let (x0, y0, x1, y1) = match self.clips.last() {
+            Some(&[px, py, pw, ph]) => (
+                x0.max(px),
+                y0.max(py),
+                x1.min(px + pw).max(x0.max(px)),
+                y1.min(py + ph).max(y0.max(py)),
+            ),
+            None => (x0, y0, x1, y1),
+        };
+        self.clips.push([x0, y0, x1 - x0, y1 - y0]);
+        self.start_run();
+    }
+    pub fn pop_clip(&mut self) -> bool {
+        let popped = self.clips.pop().is_some();
+        self.start_run();
+        popped
+    }
+    fn start_run(&mut self) {
+        let run = DrawRun {
+            layer: self.layer,
+            clip: self.clips.last().copied(),
+            target: self.target,
+            source: self.source,
+            start: self.quads.len(),
+        };
+        match self.runs.last_mut() {
+            // Nothing was drawn since the last change, so just replace it
+            Some(last) if last.start == run.start => *last = run,
+            _ => self.runs.push(run),
+        }
+    }
+    /// Stably sorts quads by layer, keeping submission order within a layer,
+    /// and groups them into batches that share a clip rectangle
+    pub fn finish_frame(&mut self) {
+        self.batches.clear();
+        if self.runs.is_empty() {
+            self.batches.push(crate::gpu::DrawBatch {
+                quads: 0..self.quads.len() as u32,
+                clip: None,
+                target: 0,
+                source: 0,
+            });
+            return;
+        }
+        let mut runs = Vec::with_capacity(self.runs.len() + 1);
+        let mut prev = DrawRun {
+            layer: 0,
+            clip: None,
+            target: 0,
+            source: 0,
+            start: 0,
+        };
+        for &run in &self.runs {
+            runs.push((prev, prev.start..run.start));
+            prev = run;
+        }
+        runs.push((prev, prev.start..self.quads.len()));
+        runs.sort_by_key(|(run, _)| run.layer);
+        let mut quads = Vec::with_capacity(self.quads.len());
+        for (run, range) in runs {
+            if range.is_empty() {
+                continue;
+            }
+            let start = quads.len() as u32;
+            quads.extend_from_slice(&self.quads[range]);
+            let end = quads.len() as u32;
+            match self.batches.last_mut() {
+                Some(batch)
+                    if batch.clip == run.clip
+                        && batch.target == run.target
+                        && batch.source == run.source =>
+                {
+                    batch.quads.end = end
+                }
+                _ => self.batches.push(crate::gpu::DrawBatch {
+                    quads: start..end,
+                    clip: run.clip,
+                    target: run.target,
+                    source: run.source,
+                }),
+            }
+        }
+        self.quads = quads;
+    }
+    /// Redirects drawing to an offscreen target, 0 goes back to the canvas
+    pub fn set_target(&mut self, id: u32) {
+        if id != self.target {
+            self.target = id;
+            self.start_run();
+        }
+    }
+    /// Draws the whole of an offscreen target stretched over a rect
+    pub fn draw_target(&mut self, id: u32, rect: [f32; 4]) {
+        let [w, h] = self.targets[id as usize - 1].size;
+        let mut quad = crate::gpu::QuadVertex::new(rect).tex_rect([0., 0., w as f32, h as f32]);
+        // Targets are bound as a one-page texture array
+        quad.page = 0;
+        self.source = id;
+        self.start_run();
+        self.push_quad(quad);
+        self.source = 0;
+        self.start_run();
+    }
+    /// Palette flags for a quad, when the cart is drawing with palette indices
+    pub fn palette_flags(&self, flags: u32) -> u32 {
+        if self.palette_mode {
+            flags
+        } else {
+            0
+        }
     }
 }
 
@@ -65,11 +631,7 @@ impl App {
                 let mem = mem.data_mut(&mut self.store);
                 let p1_input: [u8; std::mem::size_of::<crate::input::UserInput>()] =
                     bytemuck::cast(p1_input);
-                let mut i = ptr;
-                for byte in p1_input {
-                    mem[i] = byte;
-                    i += 1;
-                }
+                mem[ptr..ptr + p1_input.len()].copy_from_slice(&p1_input);
             } else {
                 println!("Couldn't get memory")
             }
@@ -84,13 +646,99 @@ impl App {
                 Err(err) => println!("{:?}", err),
             }
         }
-        self.run.call(&mut self.store, ())
+        let result = self.run.call(&mut self.store, ());
+        self.store.data_mut().finish_frame();
+        result
+    }
+    pub fn read_vertex_data(&self, cb: impl FnOnce(&[u8], &[crate::gpu::DrawBatch])) {
+        let state = self.store.data();
+        cb(bytemuck::cast_slice(&state.quads), &state.batches)
+    }
+    /// Passes the cart's framebuffer to `cb`, if it exports a `GRAINBOY_FRAMEBUFFER` pointer
+    ///
+    /// The framebuffer is `Canvas::SIZE` rgba pixels, row by row. A null pointer
+    /// turns it off for the frame.
+    pub fn read_framebuffer(&mut self, cb: impl FnOnce(&[u8]) -> Result<()>) -> Result<()> {
+        let Some(global) = self
+            .instance
+            .get_global(&mut self.store, "GRAINBOY_FRAMEBUFFER")
+        else {
+            return Ok(());
+        };
+        let ptr = match global.get(&mut self.store).i32() {
+            Some(0) => return Ok(()),
+            Some(ptr) => ptr as u32 as usize,
+            None => anyhow::bail!("GRAINBOY_FRAMEBUFFER has to be an i32 pointer"),
+        };
+        let Some(mem) = self.instance.get_memory(&mut self.store, "memory") else {
+            anyhow::bail!("failed to find host memory");
+        };
+        let [width, height] = crate::gpu::Canvas::SIZE;
+        let len = (width * height * 4) as usize;
+        let data = mem.data(&self.store);
+        match data.get(ptr..).and_then(|arr| arr.get(..len)) {
+            Some(pixels) => cb(pixels),
+            None => anyhow::bail!(
+                "framebuffer at {ptr} needs {len} bytes, memory is only {} bytes",
+                data.len()
+            ),
+        }
+    }
+    /// Updates per second the cart wants, if it exports a `GRAINBOY_UPDATE_RATE` i32
+    pub fn update_rate(&mut self) -> Option<u32> {
+        let global = self
+            .instance
+            .get_global(&mut self.store, "GRAINBOY_UPDATE_RATE")?;
+        match global.get(&mut self.store).i32() {
+            Some(rate) if rate > 0 => Some(rate as u32),
+            _ => None,
+        }
+    }
+    /// Offscreen targets the cart created, and which ones it cleared this frame
+    pub fn targets(&self) -> &[crate::gpu::TargetState] {
+        &self.store.data().targets
+    }
+    /// Canvas zoom requested by the cart
+    pub fn zoom(&self) -> f32 {
+        self.store.data().zoom
+    }
+    /// Returns the palette if the cart changed it since the last call
+    pub fn take_palette(&mut self) -> Option<crate::gpu::Palette> {
+        let state = self.store.data_mut();
+        if state.palette_dirty {
+            state.palette_dirty = false;
+            Some(state.palette)
+        } else {
+            None
+        }
     }
-    pub fn read_vertex_data(&self, cb: impl FnOnce(&[u8]) -> ()) {
-        cb(&bytemuck::cast_slice(&self.store.data().quads))
+    /// Returns the spritesheet changes the cart made since the last call
+    pub fn take_pages(&mut self) -> Vec<crate::gpu::PageWrite> {
+        let state = self.store.data_mut();
+        let glyphs = state.fonts.atlas.take_writes();
+        for write in &glyphs {
+            state.pixels.apply(write);
+        }
+        let mut pages = std::mem::take(&mut state.pages);
+        pages.extend(glyphs);
+        // The CPU copy already has everything above applied, so its edits go last
+        pages.extend(state.pixels.flush());
+        pages
     }
     pub fn clear_vertex_data(&mut self) {
-        self.store.data_mut().quads.clear();
+        let state = self.store.data_mut();
+        state.quads.clear();
+        state.layer = 0;
+        state.clips.clear();
+        state.runs.clear();
+        state.batches.clear();
+        state.transforms.clear();
+        state.target = 0;
+        state.source = 0;
+        for target in &mut state.targets {
+            target.clear = None;
+        }
+        state.fonts.atlas.begin_frame();
     }
 }
 
@@ -119,7 +767,7 @@ fn create_runtime(
             let data = mem
                 .data(&caller)
                 .get(ptr as usize..)
-                .and_then(|arr| arr.get(..len as u32 as usize));
+                .and_then(|arr| arr.get(..len as usize));
             let string = match data {
                 Some(data) => match std::str::from_utf8(data) {
                     Ok(s) => s,
@@ -144,7 +792,53 @@ fn create_runtime(
             let state = caller.data_mut();
             let mut quad = QuadVertex::new([x as f32, y as f32, w as f32, h as f32]);
             quad.fill = fill;
-            state.quads.push(quad);
+            quad.palette = state.palette_flags(QuadVertex::PALETTE_FILL);
+            state.push_quad(quad);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_quads(ptr: u32, count: u32)
+    //
+    // `ptr` points at a u32 struct version followed by `count` quads, see `CartQuad` for
+    // the layout. Much cheaper than a call per quad for particles and the like.
+    // Nothing is drawn if any quad is invalid. Like draw_sprite, sprite quads ignore
+    // palette mode, their tint is always an rgba color.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_quads", {
+        |mut caller: wasmtime::Caller<'_, HostState>, ptr: u32, count: u32| {
+            const SIZE: usize = std::mem::size_of::<CartQuad>();
+            let mem = match caller.get_export("memory") {
+                Some(wasmtime::Extern::Memory(mem)) => mem,
+                _ => anyhow::bail!("failed to find host memory"),
+            };
+            let len = 4 + count as usize * SIZE;
+            let data = match mem
+                .data(&caller)
+                .get(ptr as usize..)
+                .and_then(|arr| arr.get(..len))
+            {
+                Some(data) => data.to_vec(),
+                None => anyhow::bail!("pointer/length out of bounds"),
+            };
+            let version: u32 = bytemuck::pod_read_unaligned(&data[..4]);
+            if version != CartQuad::VERSION {
+                anyhow::bail!("unsupported quad version {version}");
+            }
+            let state = caller.data_mut();
+            let palette = state.palette_flags(crate::gpu::QuadVertex::PALETTE_FILL);
+            // Check every quad before drawing any, so a bad one doesn't leave half a batch
+            let mut quads = Vec::with_capacity(count as usize);
+            for (i, bytes) in data[4..].chunks_exact(SIZE).enumerate() {
+                let cart_quad: CartQuad = bytemuck::pod_read_unaligned(bytes);
+                let Some(quad) = cart_quad.to_quad(palette) else {
+                    anyhow::bail!("quad {i} is invalid: {cart_quad:?}");
+                };
+                quads.push(quad);
+            }
+            for quad in quads {
+                state.push_quad(quad);
+            }
             Ok(())
         }
     })?;
@@ -155,10 +849,111 @@ fn create_runtime(
         |mut caller: wasmtime::Caller<'_, HostState>, x: i32, y: i32, diameter: u32, fill: u32| {
             use crate::gpu::QuadVertex;
             let state = caller.data_mut();
+            let radius = diameter.min(255);
             let mut quad = QuadVertex::new([x as f32, y as f32, diameter as f32, diameter as f32])
-                .border_radius([(diameter, diameter); 4]);
+                .border_radius([(radius, radius); 4]);
             quad.fill = fill;
-            state.quads.push(quad);
+            quad.palette = state.palette_flags(QuadVertex::PALETTE_FILL);
+            state.push_quad(quad);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_rect_rounded(x: i32, y: i32, w: u32, h: u32, radii: u32, fill: u32)
+    //
+    // `radii` packs one byte per corner: tl | tr << 8 | br << 16 | bl << 24
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_rect_rounded", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         x: i32,
+         y: i32,
+         w: u32,
+         h: u32,
+         radii: u32,
+         fill: u32| {
+            use crate::gpu::QuadVertex;
+            let state = caller.data_mut();
+            let mut quad = QuadVertex::new([x as f32, y as f32, w as f32, h as f32]);
+            quad.border_radius = [radii, radii];
+            quad.fill = fill;
+            quad.palette = state.palette_flags(QuadVertex::PALETTE_FILL);
+            state.push_quad(quad);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_rect_outline(x: i32, y: i32, w: u32, h: u32, size: u32, color: u32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_rect_outline", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         x: i32,
+         y: i32,
+         w: u32,
+         h: u32,
+         size: u32,
+         color: u32| {
+            use crate::gpu::QuadVertex;
+            let state = caller.data_mut();
+            let size = size.min(255);
+            let mut quad = QuadVertex::new([x as f32, y as f32, w as f32, h as f32])
+                .border([size; 4], [color; 4]);
+            quad.palette = state.palette_flags(QuadVertex::PALETTE_BORDER_COLOR);
+            state.push_quad(quad);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_circ_outline(x: i32, y: i32, diameter: u32, size: u32, color: u32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_circ_outline", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         x: i32,
+         y: i32,
+         diameter: u32,
+         size: u32,
+         color: u32| {
+            use crate::gpu::QuadVertex;
+            let state = caller.data_mut();
+            let radius = diameter.min(255);
+            let size = size.min(255);
+            let mut quad = QuadVertex::new([x as f32, y as f32, diameter as f32, diameter as f32])
+                .border_radius([(radius, radius); 4])
+                .border([size; 4], [color; 4]);
+            quad.palette = state.palette_flags(QuadVertex::PALETTE_BORDER_COLOR);
+            state.push_quad(quad);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_box(x: i32, y: i32, w: u32, h: u32, fill: u32, radii: u32,
+    //                    border_sizes: u32, top: u32, right: u32, bottom: u32, left: u32)
+    //
+    // `radii` packs one byte per corner: tl | tr << 8 | br << 16 | bl << 24
+    // `border_sizes` packs one byte per side: top | right << 8 | bottom << 16 | left << 24
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_box", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         x: i32,
+         y: i32,
+         w: u32,
+         h: u32,
+         fill: u32,
+         radii: u32,
+         border_sizes: u32,
+         top: u32,
+         right: u32,
+         bottom: u32,
+         left: u32| {
+            use crate::gpu::QuadVertex;
+            let state = caller.data_mut();
+            let mut quad = QuadVertex::new([x as f32, y as f32, w as f32, h as f32]);
+            quad.fill = fill;
+            quad.border_radius = [radii, radii];
+            quad.border_size = border_sizes;
+            quad.border_color = [top, right, bottom, left];
+            quad.palette =
+                state.palette_flags(QuadVertex::PALETTE_FILL | QuadVertex::PALETTE_BORDER_COLOR);
+            state.push_quad(quad);
             Ok(())
         }
     })?;
@@ -175,18 +970,181 @@ fn create_runtime(
          sy: u32| {
             use crate::gpu::QuadVertex;
             let state = caller.data_mut();
-            let mut quad = QuadVertex::new([x as f32, y as f32, w as f32, h as f32]).tex_rect([
-                sx as f32,
-                (128 + sy) as f32,
-                w as f32,
-                h as f32,
-            ]);
-            state.quads.push(quad);
+            let mut quad = QuadVertex::new([x as f32, y as f32, w as f32, h as f32])
+                .tex_rect([sx as f32, sy as f32, w as f32, h as f32]);
+            quad.page = crate::gpu::Spritesheet::SPRITE_PAGE;
+            state.push_quad(quad);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_sprite_ex(x: i32, y: i32, w: u32, h: u32, page: u32, sx: u32, sy: u32,
+    //                          sw: u32, sh: u32, flags: u32, rotation: f32, origin_x: f32,
+    //                          origin_y: f32, tint: u32)
+    //
+    // `page` is a spritesheet page, 0 holds the fonts and 1 the built-in sprites
+    // `flags`: 1 = flip horizontally, 2 = flip vertically
+    // `rotation` is in radians around (origin_x, origin_y), relative to the top-left of the
+    // destination rect. `tint` is an rgba color multiplied with the sprite, alpha included.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_sprite_ex", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         x: i32,
+         y: i32,
+         w: u32,
+         h: u32,
+         page: u32,
+         sx: u32,
+         sy: u32,
+         sw: u32,
+         sh: u32,
+         flags: u32,
+         rotation: f32,
+         origin_x: f32,
+         origin_y: f32,
+         tint: u32| {
+            use crate::gpu::{QuadVertex, Spritesheet};
+            if page >= Spritesheet::PAGES {
+                anyhow::bail!("sprite page {page} doesn't exist");
+            }
+            let state = caller.data_mut();
+            let mut quad = QuadVertex::new([x as f32, y as f32, w as f32, h as f32])
+                .tex_rect([sx as f32, sy as f32, sw as f32, sh as f32])
+                .flip(flags & 1 != 0, flags & 2 != 0);
+            quad.page = page;
+            quad.rotation_base = rotation;
+            quad.rotation_origin = [origin_x, origin_y];
+            quad.tint = tint;
+            state.push_quad(quad);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::load_sprite_page(page: u32, ptr: u32, len: u32)
+    //
+    // `ptr` points at an encoded PNG or GIF no larger than a page (256x1280). Loading
+    // page 0 replaces the built-in fonts, fonts from load_font can't be drawn after that.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "load_sprite_page", {
+        |mut caller: wasmtime::Caller<'_, HostState>, page: u32, ptr: u32, len: u32| {
+            use crate::gpu::Spritesheet;
+            if page >= Spritesheet::PAGES {
+                anyhow::bail!("sprite page {page} doesn't exist");
+            }
+            let mem = match caller.get_export("memory") {
+                Some(wasmtime::Extern::Memory(mem)) => mem,
+                _ => anyhow::bail!("failed to find host memory"),
+            };
+            let data = mem
+                .data(&caller)
+                .get(ptr as usize..)
+                .and_then(|arr| arr.get(..len as usize));
+            let image = match data.map(image::load_from_memory) {
+                Some(Ok(image)) => image.to_rgba8(),
+                Some(Err(err)) => anyhow::bail!("failed to decode sprite page: {err}"),
+                None => anyhow::bail!("pointer/length out of bounds"),
+            };
+            let max = Spritesheet::PAGE_EXTENT;
+            if image.width() > max.width || image.height() > max.height {
+                anyhow::bail!(
+                    "sprite pages can't be larger than {}x{}, got {}x{}",
+                    max.width,
+                    max.height,
+                    image.width(),
+                    image.height()
+                );
+            }
+            let state = caller.data_mut();
+            if page == Spritesheet::FONT_PAGE {
+                // The whole page belongs to the cart now, glyphs of loaded fonts
+                // aren't packed below the built-in fonts anymore
+                state.fonts.atlas.replace_page();
+            }
+            state.write_page(crate::gpu::PageWrite::Replace(page, image));
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::sprite_set_pixel(page: u32, x: u32, y: u32, color: u32)
+    //
+    // Edits are uploaded once per frame, pixels outside of the page are ignored. Editing
+    // page 0 below the built-in fonts stops fonts from load_font being drawn, like loading it.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "sprite_set_pixel", {
+        |mut caller: wasmtime::Caller<'_, HostState>, page: u32, x: u32, y: u32, color: u32| {
+            use crate::gpu::Spritesheet;
+            if page >= Spritesheet::PAGES {
+                anyhow::bail!("sprite page {page} doesn't exist");
+            }
+            let state = caller.data_mut();
+            if page == Spritesheet::FONT_PAGE && x < Spritesheet::PAGE_EXTENT.width {
+                state.fonts.atlas.overwrite_rows(y..y.saturating_add(1));
+            }
+            state.pixels.set(page, x, y, color);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::sprite_get_pixel(page: u32, x: u32, y: u32) -> u32
+    //
+    // Includes edits made this frame, pixels outside of the page are transparent
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "sprite_get_pixel", {
+        |mut caller: wasmtime::Caller<'_, HostState>, page: u32, x: u32, y: u32| {
+            if page >= crate::gpu::Spritesheet::PAGES {
+                anyhow::bail!("sprite page {page} doesn't exist");
+            }
+            Ok(caller.data_mut().pixels.get(page, x, y))
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::sprite_blit_from_memory(page: u32, x: u32, y: u32, w: u32, h: u32, ptr: u32)
+    //
+    // `ptr` points at w * h rgba pixels, row by row. Whatever falls outside of the page is
+    // ignored, edits are uploaded once per frame. Page 0 is given up by the glyph atlas
+    // the same way as in sprite_set_pixel.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "sprite_blit_from_memory", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         page: u32,
+         x: u32,
+         y: u32,
+         w: u32,
+         h: u32,
+         ptr: u32| {
+            use crate::gpu::Spritesheet;
+            if page >= Spritesheet::PAGES {
+                anyhow::bail!("sprite page {page} doesn't exist");
+            }
+            let mem = match caller.get_export("memory") {
+                Some(wasmtime::Extern::Memory(mem)) => mem,
+                _ => anyhow::bail!("failed to find host memory"),
+            };
+            let len = w as usize * h as usize * 4;
+            let data = mem
+                .data(&caller)
+                .get(ptr as usize..)
+                .and_then(|arr| arr.get(..len));
+            let image = match data {
+                Some(data) => image::RgbaImage::from_raw(w, h, data.to_vec()),
+                None => anyhow::bail!("pointer/length out of bounds"),
+            };
+            let Some(image) = image else {
+                anyhow::bail!("invalid image size {w}x{h}");
+            };
+            let state = caller.data_mut();
+            if page == Spritesheet::FONT_PAGE && w > 0 && x < Spritesheet::PAGE_EXTENT.width {
+                state.fonts.atlas.overwrite_rows(y..y.saturating_add(h));
+            }
+            state.pixels.blit(page, [x, y], &image);
             Ok(())
         }
     })?;
     // ------------------------------------------------------------------------------------
     // grainboy::draw_text(x: i32, y: i32, font: u32, color: u32, text_ptr: u32, text_len: u32)
+    //
+    // Built-in fonts are 0-2 (small, medium, large) and 3-5 (proportional variants),
+    // fonts that don't exist draw as font 1. `text` can switch colors and fonts and draw icons inline, see `parse_markup`
     // ------------------------------------------------------------------------------------
     linker.func_wrap("grainboy", "draw_text", {
         |mut caller: wasmtime::Caller<'_, HostState>,
@@ -203,7 +1161,269 @@ fn create_runtime(
             let data = mem
                 .data(&caller)
                 .get(ptr as usize..)
-                .and_then(|arr| arr.get(..len as u32 as usize));
+                .and_then(|arr| arr.get(..len as usize));
+            let text = match data {
+                Some(data) => match std::str::from_utf8(data) {
+                    Ok(s) => s.to_string(),
+                    Err(_) => anyhow::bail!("invalid utf-8"),
+                },
+                None => anyhow::bail!("pointer/length out of bounds"),
+            };
+            caller
+                .data_mut()
+                .draw_markup(font, x, y, color, &text, true);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_line(x0: i32, y0: i32, x1: i32, y1: i32, thickness: u32, fill: u32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_line", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         x0: i32,
+         y0: i32,
+         x1: i32,
+         y1: i32,
+         thickness: u32,
+         fill: u32| {
+            caller
+                .data_mut()
+                .draw_line([x0, y0], [x1, y1], thickness, fill);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_tri(x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, fill: u32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_tri", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         x0: i32,
+         y0: i32,
+         x1: i32,
+         y1: i32,
+         x2: i32,
+         y2: i32,
+         fill: u32| {
+            caller
+                .data_mut()
+                .draw_poly(&[[x0, y0], [x1, y1], [x2, y2]], fill, true);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_poly(ptr: u32, count: u32, fill: u32, filled: u32)
+    //
+    // `ptr` points at `count` (x: i32, y: i32) pairs, little-endian
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_poly", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         ptr: u32,
+         count: u32,
+         fill: u32,
+         filled: u32| {
+            let mem = match caller.get_export("memory") {
+                Some(wasmtime::Extern::Memory(mem)) => mem,
+                _ => anyhow::bail!("failed to find host memory"),
+            };
+            let data = mem
+                .data(&caller)
+                .get(ptr as usize..)
+                .and_then(|arr| arr.get(..count as usize * 8));
+            let bytes = match data {
+                Some(bytes) => bytes,
+                None => anyhow::bail!("pointer/length out of bounds"),
+            };
+            let points: Vec<[i32; 2]> = bytes
+                .chunks_exact(8)
+                .map(|p| {
+                    [
+                        i32::from_le_bytes([p[0], p[1], p[2], p[3]]),
+                        i32::from_le_bytes([p[4], p[5], p[6], p[7]]),
+                    ]
+                })
+                .collect();
+            caller.data_mut().draw_poly(&points, fill, filled != 0);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::create_map(width: u32, height: u32, tile_w: u32, tile_h: u32, page: u32) -> u32
+    //
+    // Returns the new map's id, every tile starts out empty (0)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "create_map", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         width: u32,
+         height: u32,
+         tile_w: u32,
+         tile_h: u32,
+         page: u32| {
+            let map = crate::tilemap::Tilemap::new(width, height, [tile_w, tile_h], page)?;
+            let maps = &mut caller.data_mut().maps;
+            maps.push(map);
+            Ok(maps.len() as u32 - 1)
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::load_map(ptr: u32, len: u32, layer: u32, page: u32) -> u32
+    //
+    // `ptr` points at a binary map (see `Tilemap::from_binary`) or a Tiled JSON map, in
+    // which case `layer` picks the tile layer. Returns the new map's id.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "load_map", {
+        |mut caller: wasmtime::Caller<'_, HostState>, ptr: u32, len: u32, layer: u32, page: u32| {
+            let mem = match caller.get_export("memory") {
+                Some(wasmtime::Extern::Memory(mem)) => mem,
+                _ => anyhow::bail!("failed to find host memory"),
+            };
+            let data = mem
+                .data(&caller)
+                .get(ptr as usize..)
+                .and_then(|arr| arr.get(..len as usize));
+            let map = match data {
+                Some(data) => crate::tilemap::Tilemap::parse(data, layer, page)?,
+                None => anyhow::bail!("pointer/length out of bounds"),
+            };
+            let maps = &mut caller.data_mut().maps;
+            maps.push(map);
+            Ok(maps.len() as u32 - 1)
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::set_tile(id: u32, x: u32, y: u32, tile: u32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "set_tile", {
+        |mut caller: wasmtime::Caller<'_, HostState>, id: u32, x: u32, y: u32, tile: u32| {
+            let Some(map) = caller.data_mut().maps.get_mut(id as usize) else {
+                anyhow::bail!("tilemap {id} doesn't exist");
+            };
+            if !map.set(x, y, tile) {
+                anyhow::bail!("tile {x},{y} is outside of tilemap {id}");
+            }
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::get_tile(id: u32, x: u32, y: u32) -> u32
+    //
+    // Tiles outside of the map are empty (0)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "get_tile", {
+        |caller: wasmtime::Caller<'_, HostState>, id: u32, x: u32, y: u32| {
+            let Some(map) = caller.data().maps.get(id as usize) else {
+                anyhow::bail!("tilemap {id} doesn't exist");
+            };
+            Ok(map.get(x, y).unwrap_or(0))
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_map(id: u32, x: i32, y: i32, scroll_x: i32, scroll_y: i32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_map", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         id: u32,
+         x: i32,
+         y: i32,
+         scroll_x: i32,
+         scroll_y: i32| {
+            let pos = [x as f32, y as f32];
+            let scroll = [scroll_x as f32, scroll_y as f32];
+            if !caller.data_mut().draw_map(id, pos, scroll) {
+                anyhow::bail!("tilemap {id} doesn't exist");
+            }
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::load_font(id: u32, data_ptr: u32, data_len: u32, image_ptr: u32,
+    //                     image_len: u32)
+    //
+    // `data` is either a BDF font or a JSON descriptor (see `Font::from_json`) of the
+    // glyphs in `image`, an encoded PNG. `image` is ignored for BDF fonts.
+    // Ids 0-5 are the built-in fonts, loading a font with one of those ids replaces it.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "load_font", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         id: u32,
+         data_ptr: u32,
+         data_len: u32,
+         image_ptr: u32,
+         image_len: u32| {
+            let mem = match caller.get_export("memory") {
+                Some(wasmtime::Extern::Memory(mem)) => mem,
+                _ => anyhow::bail!("failed to find host memory"),
+            };
+            let memory = mem.data(&caller);
+            let data = memory
+                .get(data_ptr as usize..)
+                .and_then(|arr| arr.get(..data_len as usize));
+            let image = memory
+                .get(image_ptr as usize..)
+                .and_then(|arr| arr.get(..image_len as usize));
+            let font = match (data, image) {
+                (Some(data), Some(image)) => crate::text::Font::parse(data, image)?,
+                _ => anyhow::bail!("pointer/length out of bounds"),
+            };
+            caller.data_mut().fonts.insert(id, font);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::measure_text(font: u32, text_ptr: u32, text_len: u32) -> u32
+    //
+    // Returns the size that draw_text would take up, markup included, packed as
+    // width | height << 16
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "measure_text", {
+        |mut caller: wasmtime::Caller<'_, HostState>, font: u32, ptr: u32, len: u32| {
+            let mem = match caller.get_export("memory") {
+                Some(wasmtime::Extern::Memory(mem)) => mem,
+                _ => anyhow::bail!("failed to find host memory"),
+            };
+            let data = mem
+                .data(&caller)
+                .get(ptr as usize..)
+                .and_then(|arr| arr.get(..len as usize));
+            let text = match data {
+                Some(data) => match std::str::from_utf8(data) {
+                    Ok(s) => s.to_string(),
+                    Err(_) => anyhow::bail!("invalid utf-8"),
+                },
+                None => anyhow::bail!("pointer/length out of bounds"),
+            };
+            let [w, h] = caller.data_mut().draw_markup(font, 0, 0, 0, &text, false);
+            Ok(w.min(0xffff) | h.min(0xffff) << 16)
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_text_box(x: i32, y: i32, w: u32, h: u32, font: u32, color: u32,
+    //                         flags: u32, line_spacing: i32, text_ptr: u32, text_len: u32)
+    //
+    // Wraps text at `w`, lines below `h` are dropped unless `h` is 0
+    // Unlike draw_text, markup isn't parsed, braces are drawn as-is
+    // `flags`: 0 = align left, 1 = align center, 2 = align right,
+    //          | 4 = end with "..." when lines are dropped
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_text_box", {
+        |mut caller: wasmtime::Caller<'_, HostState>,
+         x: i32,
+         y: i32,
+         w: u32,
+         h: u32,
+         font: u32,
+         color: u32,
+         flags: u32,
+         line_spacing: i32,
+         ptr: u32,
+         len: u32| {
+            let mem = match caller.get_export("memory") {
+                Some(wasmtime::Extern::Memory(mem)) => mem,
+                _ => anyhow::bail!("failed to find host memory"),
+            };
+            let data = mem
+                .data(&caller)
+                .get(ptr as usize..)
+                .and_then(|arr| arr.get(..len as usize));
             let text = match data {
                 Some(data) => match std::str::from_utf8(data) {
                     Ok(s) => s.to_string(),
@@ -211,23 +1431,218 @@ fn create_runtime(
                 },
                 None => anyhow::bail!("pointer/length out of bounds"),
             };
+            let text_box = crate::text::TextBox::from_flags(w, h, line_spacing, flags);
             let state = caller.data_mut();
-            let mut left = x;
-            let mut top = y;
-            for c in text.chars() {
-                let (sx, sy, sw, sh) = get_glyph_coords(font as u8, c);
-                if c == '\n' {
-                    left = x;
-                    top += sh as i32;
-                } else {
-                    use crate::gpu::QuadVertex;
-                    let mut quad = QuadVertex::new([left as f32, top as f32, sw as f32, sh as f32])
-                        .tex_rect([sx as f32, sy as f32, sw as f32, sh as f32]);
-                    quad.tex_fill = color;
-                    state.quads.push(quad);
-                    left += sw as i32;
-                }
+            let lines = text_box.layout(state.fonts.get(font), &text);
+            for line in lines {
+                state.draw_text(font, x + line.x, y + line.y, color, &line.text);
+            }
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::create_target(w: u32, h: u32) -> u32
+    //
+    // Creates a transparent offscreen target and returns its id, starting from 1.
+    // Targets keep what's drawn onto them between frames.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "create_target", {
+        |mut caller: wasmtime::Caller<'_, HostState>, w: u32, h: u32| {
+            const MAX_TARGETS: usize = 16;
+            const MAX_SIZE: u32 = 2048;
+            let state = caller.data_mut();
+            if w == 0 || h == 0 || w > MAX_SIZE || h > MAX_SIZE {
+                anyhow::bail!("targets must be between 1x1 and {MAX_SIZE}x{MAX_SIZE}, got {w}x{h}");
+            }
+            if state.targets.len() >= MAX_TARGETS {
+                anyhow::bail!("can't create more than {MAX_TARGETS} targets");
+            }
+            state.targets.push(crate::gpu::TargetState {
+                size: [w, h],
+                clear: Some(0),
+            });
+            Ok(state.targets.len() as u32)
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::set_target(id: u32)
+    //
+    // Draws everything that follows onto an offscreen target, 0 goes back to the canvas.
+    // Targets are drawn onto before the canvas, in id order. The canvas zoom doesn't apply.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "set_target", {
+        |mut caller: wasmtime::Caller<'_, HostState>, id: u32| {
+            let state = caller.data_mut();
+            if id as usize > state.targets.len() {
+                anyhow::bail!("target {id} doesn't exist");
+            }
+            state.set_target(id);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::clear_target(id: u32, color: u32)
+    //
+    // Clears an offscreen target before anything is drawn onto it this frame
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "clear_target", {
+        |mut caller: wasmtime::Caller<'_, HostState>, id: u32, color: u32| {
+            let state = caller.data_mut();
+            let Some(target) = state.targets.get_mut((id as usize).wrapping_sub(1)) else {
+                anyhow::bail!("target {id} doesn't exist");
+            };
+            target.clear = Some(color);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::draw_target(id: u32, x: i32, y: i32, w: u32, h: u32)
+    //
+    // Draws an offscreen target like a sprite, stretched to w x h. Targets are rendered in
+    // the order they were created, so a target can only be drawn onto targets created after
+    // it (or the canvas), anything else would show last frame's pixels.
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "draw_target", {
+        |mut caller: wasmtime::Caller<'_, HostState>, id: u32, x: i32, y: i32, w: u32, h: u32| {
+            let state = caller.data_mut();
+            if id == 0 || id as usize > state.targets.len() {
+                anyhow::bail!("target {id} doesn't exist");
+            }
+            if id == state.target {
+                anyhow::bail!("target {id} can't be drawn onto itself");
             }
+            if state.target != 0 && id > state.target {
+                anyhow::bail!(
+                    "target {id} can't be drawn onto target {}, it's rendered afterwards",
+                    state.target
+                );
+            }
+            state.draw_target(id, [x as f32, y as f32, w as f32, h as f32]);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::set_layer(layer: i32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "set_layer", {
+        |mut caller: wasmtime::Caller<'_, HostState>, layer: i32| {
+            caller.data_mut().set_layer(layer);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::push_clip(x: i32, y: i32, w: u32, h: u32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "push_clip", {
+        |mut caller: wasmtime::Caller<'_, HostState>, x: i32, y: i32, w: u32, h: u32| {
+            let rect = [x as f32, y as f32, w as f32, h as f32];
+            caller.data_mut().push_clip(rect);
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::pop_clip()
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "pop_clip", {
+        |mut caller: wasmtime::Caller<'_, HostState>| {
+            if !caller.data_mut().pop_clip() {
+                anyhow::bail!("pop_clip called without a matching push_clip");
+            }
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::push_transform(x: f32, y: f32, scale: f32, rotation: f32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "push_transform", {
+        |mut caller: wasmtime::Caller<'_, HostState>, x: f32, y: f32, scale: f32, rotation: f32| {
+            if !scale.is_finite() || scale <= 0. {
+                anyhow::bail!("transform scale must be greater than zero");
+            }
+            if !x.is_finite() || !y.is_finite() || !rotation.is_finite() {
+                anyhow::bail!("transform translation and rotation must be finite");
+            }
+            caller.data_mut().push_transform(crate::gpu::Transform {
+                translate: [x, y],
+                scale,
+                rotation,
+            });
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::pop_transform()
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "pop_transform", {
+        |mut caller: wasmtime::Caller<'_, HostState>| {
+            if caller.data_mut().transforms.pop().is_none() {
+                anyhow::bail!("pop_transform called without a matching push_transform");
+            }
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::set_zoom(zoom: f32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "set_zoom", {
+        |mut caller: wasmtime::Caller<'_, HostState>, zoom: f32| {
+            if !zoom.is_finite() || zoom <= 0. {
+                anyhow::bail!("zoom must be greater than zero");
+            }
+            caller.data_mut().zoom = zoom;
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::use_palette(enabled: u32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "use_palette", {
+        |mut caller: wasmtime::Caller<'_, HostState>, enabled: u32| {
+            caller.data_mut().palette_mode = enabled != 0;
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::set_palette_entry(index: u32, color: u32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "set_palette_entry", {
+        |mut caller: wasmtime::Caller<'_, HostState>, index: u32, color: u32| {
+            let state = caller.data_mut();
+            match state.palette.colors.get_mut(index as usize) {
+                Some(entry) => *entry = color,
+                None => anyhow::bail!("palette index out of bounds"),
+            }
+            state.palette_dirty = true;
+            Ok(())
+        }
+    })?;
+    // ------------------------------------------------------------------------------------
+    // grainboy::set_palette(ptr: u32, count: u32)
+    // ------------------------------------------------------------------------------------
+    linker.func_wrap("grainboy", "set_palette", {
+        |mut caller: wasmtime::Caller<'_, HostState>, ptr: u32, count: u32| {
+            let mem = match caller.get_export("memory") {
+                Some(wasmtime::Extern::Memory(mem)) => mem,
+                _ => anyhow::bail!("failed to find host memory"),
+            };
+            if count as usize > 256 {
+                anyhow::bail!("palettes can't have more than 256 colors");
+            }
+            let mut colors = [0u8; 256 * 4];
+            let len = count as usize * 4;
+            if mem.read(&caller, ptr as usize, &mut colors[..len]).is_err() {
+                anyhow::bail!("pointer/length out of bounds");
+            }
+            let state = caller.data_mut();
+            for (entry, color) in state
+                .palette
+                .colors
+                .iter_mut()
+                .zip(colors[..len].chunks_exact(4))
+            {
+                *entry = u32::from_le_bytes([color[0], color[1], color[2], color[3]]);
+            }
+            state.palette_dirty = true;
             Ok(())
         }
     })?;
@@ -237,117 +1652,153 @@ fn create_runtime(
     Ok(instance)
 }
 
-fn get_glyph_coords(font: u8, c: char) -> (u32, u32, u32, u32) {
-    let (sw, sh) = match font {
-        0 => (5, 5),
-        1 => (5, 8),
-        2 => (8, 8),
-        _ => (5, 8),
-    };
-    let (ox, oy) = match font {
-        0 => (0, 0),
-        1 => (0, 32),
-        2 => (0, 80),
-        _ => (0, 32),
-    };
-    let (sx, sy) = match c {
-        ' ' | '\n' | '\t' => (0, 0),
-        '!' => (1, 0),
-        '"' => (2, 0),
-        '#' => (3, 0),
-        '$' => (4, 0),
-        '%' => (5, 0),
-        '&' => (6, 0),
-        '\'' | '’' => (7, 0),
-        '(' => (8, 0),
-        ')' => (9, 0),
-        '*' => (10, 0),
-        '+' => (11, 0),
-        ',' => (12, 0),
-        '-' => (13, 0),
-        '.' => (14, 0),
-        '/' => (15, 0),
-        '0' => (0, 1),
-        '1' => (1, 1),
-        '2' => (2, 1),
-        '3' => (3, 1),
-        '4' => (4, 1),
-        '5' => (5, 1),
-        '6' => (6, 1),
-        '7' => (7, 1),
-        '8' => (8, 1),
-        '9' => (9, 1),
-        ':' => (10, 1),
-        ';' => (11, 1),
-        '<' => (12, 1),
-        '=' => (13, 1),
-        '>' => (14, 1),
-        '?' => (15, 1),
-        '@' => (0, 2),
-        'A' => (1, 2),
-        'B' => (2, 2),
-        'C' => (3, 2),
-        'D' => (4, 2),
-        'E' => (5, 2),
-        'F' => (6, 2),
-        'G' => (7, 2),
-        'H' => (8, 2),
-        'I' => (9, 2),
-        'J' => (10, 2),
-        'K' => (11, 2),
-        'L' => (12, 2),
-        'M' => (13, 2),
-        'N' => (14, 2),
-        'O' => (15, 2),
-        'P' => (0, 3),
-        'Q' => (1, 3),
-        'R' => (2, 3),
-        'S' => (3, 3),
-        'T' => (4, 3),
-        'U' => (5, 3),
-        'V' => (6, 3),
-        'W' => (7, 3),
-        'X' => (8, 3),
-        'Y' => (9, 3),
-        'Z' => (10, 3),
-        '[' => (11, 3),
-        '\\' => (12, 3),
-        ']' => (13, 3),
-        '^' => (14, 3),
-        '_' => (15, 3),
-        '`' => (0, 4),
-        'a' => (1, 4),
-        'b' => (2, 4),
-        'c' => (3, 4),
-        'd' => (4, 4),
-        'e' => (5, 4),
-        'f' => (6, 4),
-        'g' => (7, 4),
-        'h' => (8, 4),
-        'i' => (9, 4),
-        'j' => (10, 4),
-        'k' => (11, 4),
-        'l' => (12, 4),
-        'm' => (13, 4),
-        'n' => (14, 4),
-        'o' => (15, 4),
-        'p' => (0, 5),
-        'q' => (1, 5),
-        'r' => (2, 5),
-        's' => (3, 5),
-        't' => (4, 5),
-        'u' => (5, 5),
-        'v' => (6, 5),
-        'w' => (7, 5),
-        'x' => (8, 5),
-        'y' => (9, 5),
-        'z' => (10, 5),
-        '{' => (11, 5),
-        '|' => (12, 5),
-        '}' => (13, 5),
-        '~' => (14, 5),
-        '🏠' => (15, 5),
-        _ => (0, 0),
-    };
-    (ox + (sx * sw), oy + (sy * sh), sw, sh)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Markup {
+        Markup::Text(text.to_string())
+    }
+
+    #[test]
+    fn transformed_maps_are_still_culled() {
+        let mut state = HostState::new();
+        let mut map = crate::tilemap::Tilemap::new(1000, 1000, [8, 8], 1).unwrap();
+        map.tiles.fill(1);
+        state.maps.push(map);
+        state.push_transform(crate::gpu::Transform {
+            translate: [128., 72.],
+            scale: 0.5,
+            rotation: 0.1,
+        });
+        assert!(state.draw_map(0, [-4000., -4000.], [0., 0.]));
+        // The canvas is 32x18 tiles at this scale, rotating it only widens the box a little
+        let count = state.quads.len();
+        assert!(count > 32 * 18 * 4 && count < 32 * 18 * 8, "{count} tiles");
+    }
+
+    #[test]
+    fn visible_rect_is_mapped_back_through_the_transform() {
+        let mut state = HostState::new();
+        state.push_transform(crate::gpu::Transform {
+            translate: [128., 72.],
+            scale: 0.5,
+            rotation: 0.,
+        });
+        assert_eq!(
+            state.untransformed_visible_rect(),
+            [-256., -144., 256., 144.]
+        );
+        state.push_transform(crate::gpu::Transform {
+            rotation: std::f32::consts::FRAC_PI_2,
+            ..crate::gpu::Transform::IDENTITY
+        });
+        let rotated = state.untransformed_visible_rect().map(f32::round);
+        assert_eq!(rotated, [-144., -256., 144., 256.]);
+    }
+
+    #[test]
+    fn cart_quads_with_undefined_values_are_rejected() {
+        let quad = CartQuad {
+            rect: [1., 2., 8., 8.],
+            color: 0xffffffff,
+            tex_rect: [0, 0, 8, 8],
+            rotation: 0.5,
+            page: 1,
+            flags: CartQuad::FLIP_X,
+            reserved: 0,
+        };
+        assert!(quad.to_quad(0).is_some());
+        let bad = [
+            CartQuad { page: 200, ..quad },
+            CartQuad { flags: 4, ..quad },
+            CartQuad {
+                reserved: 1,
+                ..quad
+            },
+            CartQuad {
+                rect: [f32::NAN, 2., 8., 8.],
+                ..quad
+            },
+            CartQuad {
+                rect: [1., 2., f32::INFINITY, 8.],
+                ..quad
+            },
+            CartQuad {
+                rotation: f32::NEG_INFINITY,
+                ..quad
+            },
+            CartQuad {
+                rotation: f32::NAN,
+                ..quad
+            },
+        ];
+        for quad in bad {
+            assert!(quad.to_quad(0).is_none(), "{quad:?}");
+        }
+    }
+
+    #[test]
+    fn unknown_fonts_measure_like_the_medium_font() {
+        let mut state = HostState::new();
+        let text = "Score {f:42}1234\n{f:S}ok";
+        let fallback = state.draw_markup(99, 0, 0, 0, text, false);
+        let medium = state.draw_markup(1, 0, 0, 0, &text.replace("42", "1"), false);
+        assert_eq!(fallback, medium);
+        state.draw_markup(99, 0, 0, 0, "abc", true);
+        assert_eq!(state.quads.len(), 3);
+    }
+
+    #[test]
+    fn splits_tags_from_text() {
+        assert_eq!(
+            parse_markup("a{c:ff0000}b{c}{f:PL}c{f}{i:3}"),
+            vec![
+                text("a"),
+                Markup::Color(Some(0xff0000ff)),
+                text("b"),
+                Markup::Color(None),
+                Markup::Font(Some(5)),
+                text("c"),
+                Markup::Font(None),
+                Markup::Icon(3),
+            ]
+        );
+        assert_eq!(parse_markup(""), vec![]);
+    }
+
+    #[test]
+    fn reads_tag_values() {
+        assert_eq!(
+            parse_tag("c:11223344"),
+            Some(Markup::Color(Some(0x44332211)))
+        );
+        assert_eq!(parse_tag("c:112233"), Some(Markup::Color(Some(0xff332211))));
+        assert_eq!(parse_tag("f:S"), Some(Markup::Font(Some(0))));
+        assert_eq!(parse_tag("f:12"), Some(Markup::Font(Some(12))));
+        for bad in [
+            "c:12345", "c:gg0000", "c:+fffff", "f:XL", "i", "i:-1", "x", "",
+        ] {
+            assert_eq!(parse_tag(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(
+            parse_markup("{{c}} {{{c}"),
+            vec![text("{c} {"), Markup::Color(None)]
+        );
+    }
+
+    #[test]
+    fn keeps_bad_tags_as_text() {
+        assert_eq!(parse_markup("{x} {c:red}"), vec![text("{x} {c:red}")]);
+        assert_eq!(parse_markup("a}b{c"), vec![text("a}b{c")]);
+        // A lone brace doesn't swallow the tag after it
+        assert_eq!(
+            parse_markup("{a {c}b"),
+            vec![text("{a "), Markup::Color(None), text("b")]
+        );
+    }
 }